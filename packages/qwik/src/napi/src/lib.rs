@@ -18,7 +18,8 @@ fn transform_fs(ctx: CallContext) -> Result<JsUnknown> {
     let opts = ctx.get::<JsObject>(0)?;
     let config: qwik_core::TransformFsOptions = ctx.env.from_js_value(opts)?;
 
-    let result = qwik_core::transform_fs(config).unwrap();
+    let result =
+        qwik_core::transform_fs(config).map_err(|err| napi::Error::from_reason(err.to_string()))?;
     ctx.env.to_js_value(&result)
 }
 
@@ -28,7 +29,8 @@ fn transform_modules(ctx: CallContext) -> Result<JsUnknown> {
     let opts = ctx.get::<JsObject>(0)?;
     let config: qwik_core::TransformModulesOptions = ctx.env.from_js_value(opts)?;
 
-    let result = qwik_core::transform_modules(config).unwrap();
+    let result = qwik_core::transform_modules(config)
+        .map_err(|err| napi::Error::from_reason(err.to_string()))?;
     ctx.env.to_js_value(&result)
 }
 
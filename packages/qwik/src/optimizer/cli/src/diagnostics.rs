@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use qwik_core::{Diagnostic, DiagnosticCategory, SourceLocation};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticsFormat {
+    Human,
+    Json,
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.category == DiagnosticCategory::Error)
+}
+
+/// Prints the diagnostics, JSON goes to stdout so it can be piped, the human format goes to stderr.
+pub fn report(
+    diagnostics: &[Diagnostic],
+    src_dir: &Path,
+    format: DiagnosticsFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        DiagnosticsFormat::Json => println!("{}", serde_json::to_string_pretty(diagnostics)?),
+        DiagnosticsFormat::Human => {
+            if !diagnostics.is_empty() {
+                eprint!("{}", render(diagnostics, src_dir));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Renders every diagnostic with a code frame of its highlights, followed by its suggestions.
+pub fn render(diagnostics: &[Diagnostic], src_dir: &Path) -> String {
+    let mut sources: HashMap<&str, Option<String>> = HashMap::new();
+    let mut output = String::new();
+    let mut errors = 0;
+    let mut warnings = 0;
+    for diagnostic in diagnostics {
+        let label = match diagnostic.category {
+            DiagnosticCategory::Error => {
+                errors += 1;
+                "error"
+            }
+            DiagnosticCategory::Warning => {
+                warnings += 1;
+                "warning"
+            }
+            DiagnosticCategory::SourceError => {
+                warnings += 1;
+                "source error"
+            }
+        };
+        match &diagnostic.code {
+            Some(code) => writeln!(output, "{}[{}]: {}", label, code, diagnostic.message),
+            None => writeln!(output, "{}: {}", label, diagnostic.message),
+        }
+        .unwrap();
+
+        let file: &str = diagnostic.file.as_ref();
+        let highlights = diagnostic.highlights.as_deref().unwrap_or_default();
+        if highlights.is_empty() {
+            writeln!(output, "  --> {}", file).unwrap();
+        }
+        for highlight in highlights {
+            writeln!(
+                output,
+                "  --> {}:{}:{}",
+                file, highlight.start_line, highlight.start_col
+            )
+            .unwrap();
+            let source = sources
+                .entry(file)
+                .or_insert_with(|| fs::read_to_string(src_dir.join(file)).ok());
+            if let Some(source) = source {
+                output += &code_frame(source, highlight);
+            }
+        }
+        if let Some(suggestions) = &diagnostic.suggestions {
+            for suggestion in suggestions {
+                writeln!(output, "   = help: {}", suggestion).unwrap();
+            }
+        }
+        output.push('\n');
+    }
+    writeln!(output, "{} errors, {} warnings", errors, warnings).unwrap();
+    output
+}
+
+fn code_frame(source: &str, location: &SourceLocation) -> String {
+    let gutter = location.end_line.to_string().len();
+    let mut output = format!("{:gutter$} |\n", "", gutter = gutter);
+    for (index, line) in source
+        .lines()
+        .enumerate()
+        .skip(location.start_line.saturating_sub(1))
+        .take((location.end_line + 1).saturating_sub(location.start_line))
+    {
+        let line_number = index + 1;
+        let line_len = line.chars().count();
+        let start = if line_number == location.start_line {
+            location.start_col
+        } else {
+            1
+        };
+        let end = if line_number == location.end_line {
+            location.end_col
+        } else {
+            line_len
+        };
+        writeln!(
+            output,
+            "{:>gutter$} | {}",
+            line_number,
+            line,
+            gutter = gutter
+        )
+        .unwrap();
+        if end >= start {
+            writeln!(
+                output,
+                "{:gutter$} | {}{}",
+                "",
+                " ".repeat(start - 1),
+                "^".repeat(end + 1 - start),
+                gutter = gutter
+            )
+            .unwrap();
+        }
+    }
+    output
+}
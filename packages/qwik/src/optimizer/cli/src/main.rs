@@ -2,27 +2,37 @@
 #![deny(clippy::perf)]
 #![deny(clippy::nursery)]
 
-use std::path::PathBuf;
+mod diagnostics;
+mod watch;
 
-use clap::{Arg, Command};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, Command};
+use diagnostics::DiagnosticsFormat;
 use path_absolutize::Absolutize;
-use qwik_core::{transform_fs, EmitMode, EntryStrategy, MinifyMode, TransformFsOptions};
+use qwik_core::{
+    transform_fs, transform_fs_with_cache, EmitMode, EntryStrategy, EsTarget, MinifyMode,
+    SourceMapsOption, TransformCache, TransformFsOptions,
+};
 
 struct OptimizerInput {
-    glob: Option<String>,
     manifest: Option<String>,
-    core_module: Option<String>,
-    scope: Option<String>,
     src: PathBuf,
     dest: PathBuf,
-    mode: EmitMode,
-    strategy: EntryStrategy,
-    transpile_ts: bool,
-    transpile_jsx: bool,
-    preserve_filenames: bool,
-    minify: MinifyMode,
-    sourcemaps: bool,
-    explicit_extensions: bool,
+    cache_dir: Option<PathBuf>,
+    watch: bool,
+    format: DiagnosticsFormat,
+    source_maps: SourceMapsOption,
+    options: TransformFsOptions,
+}
+
+struct CheckInput {
+    src: PathBuf,
+    cache_dir: Option<PathBuf>,
+    format: DiagnosticsFormat,
+    options: TransformFsOptions,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -38,14 +48,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             Command::new("optimize")
                 .about("takes a source directory of qwik code and outputs an optimized version that lazy loads")
                 .arg_required_else_help(true)
-                .arg(
-                    Arg::new("src")
-                        .short('s')
-                        .long("src")
-                        .default_value(".")
-                        .takes_value(true)
-                        .help("relative path to the source directory"),
-                )
+                .args(transform_args())
                 .arg(
                     Arg::new("dest")
                         .short('d')
@@ -54,13 +57,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .takes_value(true)
                         .help("relative path to the output directory"),
                 )
-                .arg(
-                    Arg::new("strategy")
-                    .long("strategy")
-                        .possible_values(["inline","single", "hook", "smart", "component"])
-                        .takes_value(true)
-                        .help("entry strategy used to group hooks"),
-                )
                 .arg(
                     Arg::new("manifest")
                         .short('m')
@@ -68,102 +64,489 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .takes_value(true)
                         .help("filename of the manifest"),
                 )
-                .arg(
-                    Arg::new("no-ts")
-                    .long("no-ts")
-                    .help("no transpile TS").takes_value(false)
-                 )
-                 .arg(
-                    Arg::new("no-jsx")
-                    .long("no-jsx")
-                    .help("no transpile JSX").takes_value(false)
-                 )
-                 .arg(Arg::new("preserve-filenames").long("preserve-filenames").help("preserves original filename").takes_value(false))
-                .arg(Arg::new("minify").long("minify").possible_values(["minify", "simplify", "none"]).takes_value(true).help("outputs minified source code"))
-                .arg(Arg::new("sourcemaps").long("sourcemaps").help("generates sourcemaps").takes_value(false))
-                .arg(Arg::new("extensions").long("extensions").help("keep explicit extensions on imports").takes_value(false)),
+                .arg(Arg::new("watch").short('w').long("watch").help("watches the source directory and optimizes the files that change").takes_value(false)),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("runs the optimizer over a source directory and reports its diagnostics without writing any output")
+                .args(transform_args()),
         )
         .get_matches();
 
     // You can check for the existence of subcommands, and if found use their
     // matches just as you would the top level app
-    if let Some(matches) = matches.subcommand_matches("optimize") {
-        // "$ myapp test" was run
-        let strategy = match matches.value_of("strategy") {
-            Some("inline") => EntryStrategy::Inline,
-            Some("hook") => EntryStrategy::Hook,
-            Some("single") => EntryStrategy::Single,
-            Some("component") => EntryStrategy::Component,
-            Some("smart") | None => EntryStrategy::Smart,
+    let success = match matches.subcommand() {
+        Some(("optimize", matches)) => {
+            let options = parse_options(matches)?;
+            optimize(OptimizerInput {
+                src: parse_src(matches, &options),
+                dest: matches.value_of_t_or_exit("dest"),
+                manifest: matches.value_of("manifest").map(|s| s.into()),
+                cache_dir: matches.value_of("cache-dir").map(|s| s.into()),
+                watch: matches.is_present("watch"),
+                format: parse_format(matches),
+                source_maps: match matches.value_of("sourcemaps") {
+                    Some("inline") => SourceMapsOption::Inline,
+                    _ => SourceMapsOption::External,
+                },
+                options,
+            })?
+        }
+        Some(("check", matches)) => {
+            let options = parse_options(matches)?;
+            check(CheckInput {
+                src: parse_src(matches, &options),
+                cache_dir: matches.value_of("cache-dir").map(|s| s.into()),
+                format: parse_format(matches),
+                options,
+            })?
+        }
+        _ => true,
+    };
+    if !success {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Arguments shared by every subcommand that runs the optimizer.
+fn transform_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("config")
+            .short('c')
+            .long("config")
+            .takes_value(true)
+            .help("path to a JSON file with the optimizer options (e.g. qwik.optimizer.json), its relative paths are resolved from its directory and flags take precedence over it"),
+        Arg::new("src")
+            .short('s')
+            .long("src")
+            .default_value(".")
+            .takes_value(true)
+            .help("relative path to the source directory"),
+        Arg::new("root-dir")
+            .long("root-dir")
+            .takes_value(true)
+            .help("root directory of the project, source maps are relative to it"),
+        Arg::new("vendor-roots")
+            .long("vendor-roots")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_value_delimiter(true)
+            .help("additional directories of third party code to optimize"),
+        Arg::new("glob")
+            .long("glob")
+            .takes_value(true)
+            .help("glob of the files to optimize"),
+        Arg::new("strategy")
+            .long("strategy")
+            .possible_values(["inline", "single", "hook", "smart", "component", "rules", "profile", "size"])
+            .takes_value(true)
+            .help("entry strategy used to group hooks"),
+        Arg::new("manual-chunks")
+            .long("manual-chunks")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_value_delimiter(true)
+            .help("assigns hooks to entries, as a list of hash=entry pairs"),
+        Arg::new("usage-profile")
+            .long("usage-profile")
+            .takes_value(true)
+            .help("path to a JSON file with the symbols requested together by real sessions, used by the profile strategy"),
+        Arg::new("min-size")
+            .long("min-size")
+            .takes_value(true)
+            .help("bytes up to which the size strategy merges the segments of an origin"),
+        Arg::new("max-size")
+            .long("max-size")
+            .takes_value(true)
+            .help("bytes over which the size strategy stops merging segments"),
+        Arg::new("inline-threshold")
+            .long("inline-threshold")
+            .takes_value(true)
+            .help("inlines the segments smaller than this many bytes in their parent module"),
+        Arg::new("previous-manifest")
+            .long("previous-manifest")
+            .takes_value(true)
+            .help("path to the manifest of a previous build, its segments keep their entry"),
+        Arg::new("mode")
+            .long("mode")
+            .possible_values(["dev", "prod", "lib"])
+            .takes_value(true)
+            .help("emit mode of the output"),
+        Arg::new("core-module")
+            .long("core-module")
+            .takes_value(true)
+            .help("module used to import the qwik runtime, defaults to @builder.io/qwik"),
+        Arg::new("scope")
+            .long("scope")
+            .takes_value(true)
+            .help("scope used to compute the symbol hashes"),
+        Arg::new("server")
+            .long("server")
+            .takes_value(false)
+            .conflicts_with("client")
+            .help("builds the server output"),
+        Arg::new("client")
+            .long("client")
+            .takes_value(false)
+            .help("builds the client output"),
+        Arg::new("strip-exports")
+            .long("strip-exports")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_value_delimiter(true)
+            .help("exports replaced with a function that throws"),
+        Arg::new("strip-ctx-name")
+            .long("strip-ctx-name")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_value_delimiter(true)
+            .help("prefixes of the ctx names whose hooks are replaced with a noop"),
+        Arg::new("reg-ctx-name")
+            .long("reg-ctx-name")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .use_value_delimiter(true)
+            .help("prefixes of the ctx names whose hooks are registered"),
+        Arg::new("strip-event-handlers")
+            .long("strip-event-handlers")
+            .help("replaces event handlers with a noop")
+            .takes_value(false),
+        Arg::new("no-ts")
+            .long("no-ts")
+            .help("no transpile TS")
+            .takes_value(false),
+        Arg::new("no-jsx")
+            .long("no-jsx")
+            .help("no transpile JSX")
+            .takes_value(false),
+        Arg::new("preserve-filenames")
+            .long("preserve-filenames")
+            .help("preserves original filename")
+            .takes_value(false),
+        Arg::new("minify")
+            .long("minify")
+            .possible_values(["minify", "simplify", "none"])
+            .takes_value(true)
+            .help("outputs minified source code"),
+        Arg::new("target")
+            .long("target")
+            .possible_values([
+                "es2017", "es2018", "es2019", "es2020", "es2021", "es2022", "esnext",
+            ])
+            .takes_value(true)
+            .help("ECMAScript version of the output, newer syntax is downleveled"),
+        Arg::new("sourcemaps")
+            .long("sourcemaps")
+            .possible_values(["external", "inline"])
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("external")
+            .help("generates sourcemaps, written to .map files or inlined as data URLs"),
+        Arg::new("sources-content")
+            .long("sources-content")
+            .help("embeds the original sources in the sourcemaps")
+            .takes_value(false),
+        Arg::new("extensions")
+            .long("extensions")
+            .help("keep explicit extensions on imports")
+            .takes_value(false),
+        Arg::new("content-hash")
+            .long("content-hash")
+            .help("emit segments and entries as [name].[contenthash].js")
+            .takes_value(false),
+        Arg::new("qrl-base")
+            .long("qrl-base")
+            .takes_value(true)
+            .help("base URL the QRLs import segments from, like https://cdn.example.com/build/"),
+        Arg::new("segment-dir")
+            .long("segment-dir")
+            .takes_value(true)
+            .help("directory and file name prefix of the segments and entries, like build/q-, a value without / is a directory"),
+        Arg::new("recover-parse-errors")
+            .long("recover-parse-errors")
+            .help("keeps transforming files with recoverable syntax errors"),
+        Arg::new("cache-dir")
+            .long("cache-dir")
+            .takes_value(true)
+            .help("directory used to persist the transform cache between runs"),
+        Arg::new("format")
+            .long("format")
+            .possible_values(["human", "json"])
+            .default_value("human")
+            .takes_value(true)
+            .help("format of the reported diagnostics"),
+    ]
+}
+
+fn parse_src(matches: &ArgMatches, options: &TransformFsOptions) -> PathBuf {
+    if matches.occurrences_of("src") == 0 && matches.is_present("config") {
+        PathBuf::from(&options.src_dir)
+    } else {
+        matches.value_of_t_or_exit("src")
+    }
+}
+
+fn parse_format(matches: &ArgMatches) -> DiagnosticsFormat {
+    match matches.value_of("format") {
+        Some("json") => DiagnosticsFormat::Json,
+        Some("human") | None => DiagnosticsFormat::Human,
+        _ => panic!("Invalid format option"),
+    }
+}
+
+/// Reads the options from the `--config` file, or the CLI defaults, and applies the flags on top.
+fn parse_options(matches: &ArgMatches) -> Result<TransformFsOptions, Box<dyn std::error::Error>> {
+    let mut options = match matches.value_of("config") {
+        Some(config) => resolve_config_paths(
+            read_json(config, "config file")?,
+            Path::new(config).parent().unwrap_or_else(|| Path::new("")),
+        ),
+        None => TransformFsOptions::default(),
+    };
+    apply_enum_flags(matches, &mut options);
+    apply_value_flags(matches, &mut options)?;
+    apply_switch_flags(matches, &mut options);
+    Ok(options)
+}
+
+fn apply_enum_flags(matches: &ArgMatches, options: &mut TransformFsOptions) {
+    if let Some(strategy) = matches.value_of("strategy") {
+        options.entry_strategy = match strategy {
+            "inline" => EntryStrategy::Inline,
+            "hook" => EntryStrategy::Hook,
+            "single" => EntryStrategy::Single,
+            "component" => EntryStrategy::Component,
+            "smart" => EntryStrategy::Smart,
+            "rules" => EntryStrategy::Rules,
+            "profile" => EntryStrategy::Profile,
+            "size" => EntryStrategy::Size,
             _ => panic!("Invalid strategy option"),
         };
-
-        let minify = match matches.value_of("minify") {
-            Some("none") => MinifyMode::None,
-            Some("simplify") | None => MinifyMode::Simplify,
+    }
+    if let Some(minify) = matches.value_of("minify") {
+        options.minify = match minify {
+            "none" => MinifyMode::None,
+            "simplify" => MinifyMode::Simplify,
+            "minify" => MinifyMode::Minify,
             _ => panic!("Invalid minify option"),
         };
-
-        let mode = match matches.value_of("mode") {
-            Some("dev") => EmitMode::Dev,
-            Some("prod") => EmitMode::Prod,
-            Some("lib") | None => EmitMode::Lib,
+    }
+    if let Some(target) = matches.value_of("target") {
+        options.target = match target {
+            "es2017" => EsTarget::Es2017,
+            "es2018" => EsTarget::Es2018,
+            "es2019" => EsTarget::Es2019,
+            "es2020" => EsTarget::Es2020,
+            "es2021" => EsTarget::Es2021,
+            "es2022" => EsTarget::Es2022,
+            "esnext" => EsTarget::EsNext,
+            _ => panic!("Invalid target option"),
+        };
+    }
+    if let Some(mode) = matches.value_of("mode") {
+        options.mode = match mode {
+            "dev" => EmitMode::Dev,
+            "prod" => EmitMode::Prod,
+            "lib" => EmitMode::Lib,
             _ => panic!("Invalid mode option"),
         };
-        optimize(OptimizerInput {
-            src: matches.value_of_t_or_exit("src"),
-            dest: matches.value_of_t_or_exit("dest"),
-            manifest: matches.value_of("manifest").map(|s| s.into()),
-            core_module: matches.value_of("core_module").map(|s| s.into()),
-            scope: matches.value_of("scope").map(|s| s.into()),
-            mode,
-            glob: None,
-            strategy,
-            minify,
-            explicit_extensions: matches.is_present("extensions"),
-            transpile_jsx: !matches.is_present("no-jsx"),
-            transpile_ts: !matches.is_present("no-ts"),
-            preserve_filenames: matches.is_present("preserve-filenames"),
-            sourcemaps: matches.is_present("sourcemaps"),
-        })?;
+    }
+}
+
+fn apply_value_flags(
+    matches: &ArgMatches,
+    options: &mut TransformFsOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(manual_chunks) = matches.values_of("manual-chunks") {
+        let mut map = HashMap::new();
+        for pair in manual_chunks {
+            let (hash, entry) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid manual chunk {}, expected hash=entry", pair))?;
+            map.insert(hash.to_string(), entry.into());
+        }
+        options.manual_chunks = Some(map);
+    }
+    if let Some(usage_profile) = matches.value_of("usage-profile") {
+        options.usage_profile = Some(read_json(usage_profile, "usage profile")?);
+    }
+    if matches.is_present("min-size") || matches.is_present("max-size") {
+        let mut size_limits = options.size_limits.unwrap_or_default();
+        if matches.is_present("min-size") {
+            size_limits.min_size = matches.value_of_t_or_exit("min-size");
+        }
+        if matches.is_present("max-size") {
+            size_limits.max_size = matches.value_of_t_or_exit("max-size");
+        }
+        options.size_limits = Some(size_limits);
+    }
+    if matches.is_present("inline-threshold") {
+        options.inline_threshold = Some(matches.value_of_t_or_exit("inline-threshold"));
+    }
+    if let Some(previous_manifest) = matches.value_of("previous-manifest") {
+        options.previous_manifest = Some(read_json(previous_manifest, "previous manifest")?);
+    }
+    if let Some(root_dir) = matches.value_of("root-dir") {
+        options.root_dir = Some(root_dir.into());
+    }
+    if let Some(vendor_roots) = matches.values_of("vendor-roots") {
+        options.vendor_roots = vendor_roots.map(Into::into).collect();
+    }
+    if let Some(glob) = matches.value_of("glob") {
+        options.glob = Some(glob.into());
+    }
+    if let Some(core_module) = matches.value_of("core-module") {
+        options.core_module = Some(core_module.into());
+    }
+    if let Some(scope) = matches.value_of("scope") {
+        options.scope = Some(scope.into());
+    }
+    if let Some(strip_exports) = matches.values_of("strip-exports") {
+        options.strip_exports = Some(strip_exports.map(Into::into).collect());
+    }
+    if let Some(strip_ctx_name) = matches.values_of("strip-ctx-name") {
+        options.strip_ctx_name = Some(strip_ctx_name.map(Into::into).collect());
+    }
+    if let Some(reg_ctx_name) = matches.values_of("reg-ctx-name") {
+        options.reg_ctx_name = Some(reg_ctx_name.map(Into::into).collect());
+    }
+    if let Some(qrl_base) = matches.value_of("qrl-base") {
+        options.qrl_base = Some(qrl_base.into());
+    }
+    if let Some(segment_dir) = matches.value_of("segment-dir") {
+        options.segment_dir = Some(segment_dir.into());
     }
     Ok(())
 }
 
-fn optimize(
-    optimizer_input: OptimizerInput,
-) -> Result<qwik_core::TransformOutput, Box<dyn std::error::Error>> {
+fn apply_switch_flags(matches: &ArgMatches, options: &mut TransformFsOptions) {
+    if matches.is_present("server") {
+        options.is_server = Some(true);
+    } else if matches.is_present("client") {
+        options.is_server = Some(false);
+    }
+    if matches.is_present("strip-event-handlers") {
+        options.strip_event_handlers = true;
+    }
+    if matches.is_present("no-ts") {
+        options.transpile_ts = false;
+    }
+    if matches.is_present("no-jsx") {
+        options.transpile_jsx = false;
+    }
+    if matches.is_present("preserve-filenames") {
+        options.preserve_filenames = true;
+    }
+    if matches.is_present("sourcemaps") {
+        options.source_maps = true;
+    }
+    if matches.is_present("sources-content") {
+        options.sources_content = true;
+    }
+    if matches.is_present("extensions") {
+        options.explicit_extensions = true;
+    }
+    if matches.is_present("content-hash") {
+        options.content_hash = true;
+    }
+    if matches.is_present("recover-parse-errors") {
+        options.recover_parse_errors = true;
+    }
+}
+
+/// Reads and parses the JSON file at `path`, `what` describes it in the errors.
+fn read_json<T: serde::de::DeserializeOwned>(path: &str, what: &str) -> Result<T, String> {
+    let json =
+        fs::read_to_string(path).map_err(|err| format!("Reading {} {}: {}", what, path, err))?;
+    serde_json::from_str(&json).map_err(|err| format!("Parsing {} {}: {}", what, path, err))
+}
+
+/// Resolves the relative paths of a config file from the directory holding it, rather than from
+/// the current directory.
+fn resolve_config_paths(options: TransformFsOptions, config_dir: &Path) -> TransformFsOptions {
+    let resolve = |path: &str| config_dir.join(path).to_string_lossy().to_string();
+    TransformFsOptions {
+        src_dir: resolve(&options.src_dir),
+        root_dir: options.root_dir.as_deref().map(resolve),
+        vendor_roots: options
+            .vendor_roots
+            .iter()
+            .map(|path| resolve(path))
+            .collect(),
+        ..options
+    }
+}
+
+/// Runs the optimizer and writes its output, returns `false` if the build emitted errors, in which
+/// case nothing is written.
+fn optimize(optimizer_input: OptimizerInput) -> Result<bool, Box<dyn std::error::Error>> {
     let current_dir = std::env::current_dir()?;
     let src_dir = current_dir.join(optimizer_input.src).canonicalize()?;
+    let dest = current_dir
+        .join(optimizer_input.dest)
+        .absolutize()?
+        .to_path_buf();
 
-    let result = transform_fs(TransformFsOptions {
+    let config = TransformFsOptions {
         src_dir: src_dir.to_string_lossy().to_string(),
-        vendor_roots: vec![],
-        glob: optimizer_input.glob,
-        source_maps: optimizer_input.sourcemaps,
-        minify: optimizer_input.minify,
-        transpile_jsx: optimizer_input.transpile_jsx,
-        transpile_ts: optimizer_input.transpile_ts,
-        preserve_filenames: optimizer_input.preserve_filenames,
-        entry_strategy: optimizer_input.strategy,
-        explicit_extensions: optimizer_input.explicit_extensions,
-        core_module: optimizer_input.core_module,
-        root_dir: None,
-
-        mode: optimizer_input.mode,
-        scope: optimizer_input.scope,
-
-        manual_chunks: None,
-        strip_exports: None,
-        strip_ctx_name: None,
-        strip_event_handlers: false,
-        reg_ctx_name: None,
-        is_server: None,
-    })?;
-
-    result.write_to_fs(
-        &current_dir.join(optimizer_input.dest).absolutize()?,
-        optimizer_input.manifest,
-    )?;
-    Ok(result)
+        ..optimizer_input.options
+    };
+    let cache = optimizer_input
+        .cache_dir
+        .map(|cache_dir| TransformCache::with_dir(current_dir.join(cache_dir)));
+
+    if optimizer_input.watch {
+        let cache = cache.unwrap_or_default();
+        let output = watch::WatchOutput {
+            dest: &dest,
+            manifest: optimizer_input.manifest.as_deref(),
+            source_maps: optimizer_input.source_maps,
+            format: optimizer_input.format,
+        };
+        watch::watch(config, &output, &cache)?;
+        return Ok(true);
+    }
+
+    let result = match cache {
+        Some(cache) => transform_fs_with_cache(&config, &cache)?,
+        None => transform_fs(config)?,
+    };
+
+    diagnostics::report(&result.diagnostics, &src_dir, optimizer_input.format)?;
+    if diagnostics::has_errors(&result.diagnostics) {
+        return Ok(false);
+    }
+    // Status lines go to stderr, stdout only holds the JSON diagnostics
+    if let Some(diff) = &result.manifest_diff {
+        eprintln!(
+            "[qwik] {} symbols added, {} removed, {} moved since the previous manifest",
+            diff.added.len(),
+            diff.removed.len(),
+            diff.moved.len()
+        );
+    }
+    result.write_to_fs(&dest, optimizer_input.manifest, optimizer_input.source_maps)?;
+    Ok(true)
+}
+
+/// Runs the optimizer without writing any output, returns `false` if the build emitted errors.
+fn check(check_input: CheckInput) -> Result<bool, Box<dyn std::error::Error>> {
+    let current_dir = std::env::current_dir()?;
+    let src_dir = current_dir.join(check_input.src).canonicalize()?;
+    let config = TransformFsOptions {
+        src_dir: src_dir.to_string_lossy().to_string(),
+        ..check_input.options
+    };
+    let result = match check_input.cache_dir {
+        Some(cache_dir) => {
+            let cache = TransformCache::with_dir(current_dir.join(cache_dir));
+            transform_fs_with_cache(&config, &cache)?
+        }
+        None => transform_fs(config)?,
+    };
+
+    diagnostics::report(&result.diagnostics, &src_dir, check_input.format)?;
+    Ok(!diagnostics::has_errors(&result.diagnostics))
 }
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::diagnostics::{self, DiagnosticsFormat};
+use qwik_core::{
+    find_modules, transform_fs_with_cache, SourceMapsOption, TransformCache, TransformFsOptions,
+    TransformOutput,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+type Snapshot = HashMap<PathBuf, SystemTime>;
+
+/// Where and how the output of every build is written.
+pub struct WatchOutput<'a> {
+    pub dest: &'a Path,
+    pub manifest: Option<&'a str>,
+    pub source_maps: SourceMapsOption,
+    pub format: DiagnosticsFormat,
+}
+
+#[derive(Default)]
+struct SyncStats {
+    written: usize,
+    removed: usize,
+}
+
+/// Watches the source directory and the vendor roots, re-running the optimizer every time a file
+/// is added, removed or modified.
+///
+/// Unchanged files are served from `cache`, only modules whose code changed are written again, and
+/// the output of the previous build that is no longer produced (e.g. segments whose symbols
+/// disappeared) is deleted from `dest`. Builds with errors are reported and not written. Status
+/// lines go to stderr, like the human diagnostics, so stdout only holds the JSON ones.
+pub fn watch(
+    config: TransformFsOptions,
+    output: &WatchOutput,
+    cache: &TransformCache,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut written = HashMap::new();
+    let mut snapshot = scan(&config)?;
+    let mut rebuild = || build(&config, output, cache, &mut written);
+    rebuild();
+    eprintln!("[qwik] watching {} for changes", config.src_dir);
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        let next = scan(&config)?;
+        if next != snapshot {
+            snapshot = next;
+            rebuild();
+        }
+    }
+}
+
+fn scan(config: &TransformFsOptions) -> io::Result<Snapshot> {
+    let mut paths = vec![];
+    find_modules(
+        Path::new(&config.src_dir),
+        config.vendor_roots.clone(),
+        &mut paths,
+    )?;
+    Ok(paths
+        .into_iter()
+        .filter_map(|path| {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+            Some((path, modified))
+        })
+        .collect())
+}
+
+fn build(
+    config: &TransformFsOptions,
+    output: &WatchOutput,
+    cache: &TransformCache,
+    written: &mut HashMap<PathBuf, String>,
+) {
+    let start = Instant::now();
+    let misses = cache.misses();
+    let result = transform_fs_with_cache(config, cache)
+        .map_err(Box::<dyn std::error::Error>::from)
+        .and_then(|result| {
+            let src_dir = Path::new(&config.src_dir);
+            diagnostics::report(&result.diagnostics, src_dir, output.format)?;
+            if diagnostics::has_errors(&result.diagnostics) {
+                return Err("build failed, the output was not updated".into());
+            }
+            sync_to_fs(&result, output, written)
+        });
+    match result {
+        Ok(stats) => eprintln!(
+            "[qwik] transformed {} files, wrote {} files, removed {} files in {}ms",
+            cache.misses() - misses,
+            stats.written,
+            stats.removed,
+            start.elapsed().as_millis()
+        ),
+        Err(err) => eprintln!("[qwik] {}", err),
+    }
+}
+
+fn sync_to_fs(
+    result: &TransformOutput,
+    output: &WatchOutput,
+    written: &mut HashMap<PathBuf, String>,
+) -> Result<SyncStats, Box<dyn std::error::Error>> {
+    let mut stats = SyncStats::default();
+    let mut next = HashMap::with_capacity(result.modules.len());
+    for module in &result.modules {
+        for (path, contents) in module.output_files(output.source_maps) {
+            let write_path = output.dest.join(path);
+            if written.get(&write_path) != Some(&contents) {
+                if let Some(parent) = write_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&write_path, &contents)?;
+                stats.written += 1;
+            }
+            next.insert(write_path, contents);
+        }
+    }
+    for path in written.keys() {
+        if !next.contains_key(path) {
+            match fs::remove_file(path) {
+                Ok(()) => stats.removed += 1,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    *written = next;
+
+    if let Some(manifest) = output.manifest {
+        let json = serde_json::to_string(&result.get_manifest())?;
+        fs::write(output.dest.join(manifest), json)?;
+    }
+    Ok(stats)
+}
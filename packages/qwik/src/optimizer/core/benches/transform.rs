@@ -185,20 +185,36 @@ fn transform_todo_app(b: &mut Bencher) {
       "#;
       transform_modules(TransformModulesOptions {
         src_dir: "/user/qwik/src/".into(),
+          root_dir: None,
           input: vec![TransformModuleInput {
               code: code.into(),
               path: "file.tsx".into(),
+              input_source_map: None,
+              vendored: false,
           }],
           source_maps: false,
+          sources_content: false,
           explicit_extensions: false,
           minify: MinifyMode::Simplify,
+          target: EsTarget::EsNext,
           transpile_ts: true,
           transpile_jsx: true,
           preserve_filenames: false,
           manual_chunks: None,
+          entry_policy: None,
+          entry_rules: None,
+          usage_profile: None,
+          size_limits: None,
+          inline_threshold: None,
+          previous_manifest: None,
+          content_hash: false,
+          qrl_base: None,
+          segment_dir: None,
+          recover_parse_errors: false,
           entry_strategy: EntryStrategy::Single,
           mode: EmitMode::Prod,
           scope: None,
+          core_module: None,
           reg_ctx_name: None,
           strip_exports: None,
           strip_ctx_name: None,
@@ -0,0 +1,200 @@
+use crate::parse::{transform_code, TransformCodeOptions, TransformOutput};
+
+use crate::hash::stable_hasher;
+
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+#[cfg(feature = "fs")]
+use std::fs;
+#[cfg(feature = "fs")]
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+
+/// Content-addressed cache of per-file transform results.
+///
+/// Entries are keyed by the relative path of the input, and validated against a hash of the
+/// source code and the options fingerprint, so an input whose code or relevant options changed is
+/// transformed again while the rest of the batch reuses its previous modules and diagnostics.
+/// Only one entry is kept per path, which bounds memory to the size of the project.
+#[derive(Default)]
+pub struct TransformCache {
+    entries: RwLock<HashMap<String, CacheEntry>>,
+    #[cfg(feature = "fs")]
+    dir: Option<PathBuf>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    key: u64,
+    output: TransformOutput,
+    orders: Vec<u64>,
+}
+
+impl TransformCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a cache backed by `dir`, entries missing in memory are looked up on disk and every
+    /// new entry is persisted, so cold starts can reuse the results of a previous process.
+    #[cfg(feature = "fs")]
+    pub fn with_dir<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: Some(dir.as_ref().to_path_buf()),
+            ..Self::default()
+        }
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().map_or(0, |entries| entries.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut entries) = self.entries.write() {
+            entries.clear();
+        }
+    }
+
+    pub(crate) fn get_or_transform(
+        &self,
+        fingerprint: u64,
+        config: TransformCodeOptions,
+    ) -> Result<TransformOutput, Error> {
+        let path = config.relative_path.to_string();
+        let key = content_key(
+            fingerprint,
+            config.code,
+            config.input_source_map,
+            config.vendored,
+        );
+        if let Some(output) = self.get(&path, key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(output);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let output = transform_code(config)?;
+        self.insert(path, key, &output);
+        Ok(output)
+    }
+
+    fn get(&self, path: &str, key: u64) -> Option<TransformOutput> {
+        let entry = self
+            .entries
+            .read()
+            .ok()
+            .and_then(|entries| entries.get(path).cloned());
+
+        #[cfg(feature = "fs")]
+        let entry = entry.or_else(|| self.load(path));
+
+        entry
+            .filter(|entry| entry.key == key)
+            .map(|entry| entry.into_output())
+    }
+
+    fn insert(&self, path: String, key: u64, output: &TransformOutput) {
+        let entry = CacheEntry {
+            key,
+            output: output.clone(),
+            orders: output.modules.iter().map(|m| m.order).collect(),
+        };
+
+        #[cfg(feature = "fs")]
+        self.store(&path, &entry);
+
+        if let Ok(mut entries) = self.entries.write() {
+            entries.insert(path, entry);
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    fn entry_path(&self, path: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| {
+            let mut hasher = stable_hasher();
+            hasher.write(path.as_bytes());
+            dir.join(format!("{:016x}.json", hasher.finish()))
+        })
+    }
+
+    #[cfg(feature = "fs")]
+    fn load(&self, path: &str) -> Option<CacheEntry> {
+        let data = fs::read(self.entry_path(path)?).ok()?;
+        let entry: CacheEntry = serde_json::from_slice(&data).ok()?;
+        if let Ok(mut entries) = self.entries.write() {
+            entries.insert(path.to_string(), entry.clone());
+        }
+        Some(entry)
+    }
+
+    #[cfg(feature = "fs")]
+    fn store(&self, path: &str, entry: &CacheEntry) {
+        // A failure to persist the cache should never fail the build
+        if let Some(entry_path) = self.entry_path(path) {
+            if let Ok(json) = serde_json::to_vec(entry) {
+                if let Some(dir) = entry_path.parent() {
+                    let _ = fs::create_dir_all(dir);
+                }
+                let _ = fs::write(entry_path, json);
+            }
+        }
+    }
+}
+
+impl CacheEntry {
+    fn into_output(self) -> TransformOutput {
+        let mut output = self.output;
+        for (module, order) in output.modules.iter_mut().zip(self.orders) {
+            module.order = order;
+        }
+        output
+    }
+}
+
+/// Computes the fingerprint of the options that affect the output of `transform_code`.
+///
+/// The options are serialized through `serde_json::Value`, which keeps object keys sorted, so the
+/// fingerprint does not depend on the iteration order of maps like `manual_chunks`.
+pub fn options_fingerprint<T: Serialize>(options: &T) -> Result<u64, Error> {
+    let value = serde_json::to_value(options)?;
+    let mut hasher = stable_hasher();
+    hasher.write(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.write(value.to_string().as_bytes());
+    Ok(hasher.finish())
+}
+
+fn content_key(
+    fingerprint: u64,
+    code: &str,
+    input_source_map: Option<&str>,
+    vendored: bool,
+) -> u64 {
+    let mut hasher = stable_hasher();
+    hasher.write_u64(fingerprint);
+    hasher.write(code.as_bytes());
+    if let Some(input_source_map) = input_source_map {
+        hasher.write(input_source_map.as_bytes());
+    }
+    // Vendored modules report their errors with another category
+    hasher.write_u8(vendored as u8);
+    hasher.finish()
+}
@@ -1,21 +1,28 @@
 use crate::collector::{new_ident_from_id, GlobalCollect, Id, ImportKind};
+use crate::entry_strategy::SizeLimits;
+use crate::errors::TransformError;
+use crate::hash::stable_hasher;
 use crate::parse::{
-    emit_source_code, might_need_handle_watch, HookAnalysis, PathData, TransformModule,
-    TransformOutput,
+    emit_source_code, might_need_handle_watch, EmitOptions, HookAnalysis, ModuleImport, PathData,
+    TransformModule, TransformOutput,
 };
 use crate::transform::{add_handle_watch, create_synthetic_named_import};
 use crate::words::*;
 
-use std::collections::BTreeMap;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Error};
+use anyhow::Error;
 use path_slash::PathExt;
 use swc_atoms::JsWord;
 use swc_common::comments::{SingleThreadedComments, SingleThreadedCommentsMap};
-use swc_common::{sync::Lrc, SourceMap, DUMMY_SP};
+use swc_common::{sync::Lrc, BytePos, FileName, SourceFile, SourceMap, Span, DUMMY_SP};
 use swc_ecmascript::ast;
+use swc_ecmascript::parser::lexer::Lexer;
+use swc_ecmascript::parser::{EsConfig, Parser, StringInput, Syntax, TsConfig};
 use swc_ecmascript::utils::private_ident;
+use swc_ecmascript::visit::{noop_visit_type, Visit, VisitWith};
 
 macro_rules! id {
     ($ident: expr) => {
@@ -35,6 +42,8 @@ pub struct NewModuleCtx<'a> {
     pub need_handle_watch: bool,
     pub need_transform: bool,
     pub explicit_extensions: bool,
+    /// Absolute directory the segment is emitted in
+    pub segment_dir: &'a Path,
     pub leading_comments: SingleThreadedCommentsMap,
     pub trailing_comments: SingleThreadedCommentsMap,
 }
@@ -98,9 +107,9 @@ pub fn new_module(ctx: NewModuleCtx) -> Result<(ast::Module, SingleThreadedComme
                             span: DUMMY_SP,
                             value: fix_path(
                                 &ctx.path.abs_dir,
-                                &ctx.path.base_dir,
+                                ctx.segment_dir,
                                 import.source.as_ref(),
-                            )?,
+                            ),
                             raw: None,
                         }),
                         specifiers: vec![specifier],
@@ -126,9 +135,9 @@ pub fn new_module(ctx: NewModuleCtx) -> Result<(ast::Module, SingleThreadedComme
                             span: DUMMY_SP,
                             value: fix_path(
                                 &ctx.path.abs_dir,
-                                &ctx.path.base_dir,
+                                ctx.segment_dir,
                                 &format!("./{}", filename),
-                            )?,
+                            ),
                             raw: None,
                         }),
                         specifiers: vec![ast::ImportSpecifier::Named(ast::ImportNamedSpecifier {
@@ -160,11 +169,9 @@ pub fn new_module(ctx: NewModuleCtx) -> Result<(ast::Module, SingleThreadedComme
     Ok((module, comments))
 }
 
-pub fn fix_path<S: AsRef<Path>, D: AsRef<Path>>(
-    src: S,
-    dest: D,
-    ident: &str,
-) -> Result<JsWord, Error> {
+/// Rebases the relative import `ident` of a module in `dest` to a module in `src`, other imports
+/// are returned as is.
+pub fn fix_path<S: AsRef<Path>, D: AsRef<Path>>(src: S, dest: D, ident: &str) -> JsWord {
     let src = src.as_ref();
     let dest = dest.as_ref();
     if ident.starts_with('.') {
@@ -175,15 +182,35 @@ pub fn fix_path<S: AsRef<Path>, D: AsRef<Path>>(
             let relative = relative_path::RelativePath::new(&normalize);
             let final_path = relative.join(ident).normalize();
             let final_str = final_path.as_str();
-            return Ok(if final_str.starts_with('.') {
+            return if final_str.starts_with('.') {
                 JsWord::from(final_str)
             } else {
                 JsWord::from(format!("./{}", final_str))
-            });
+            };
         }
     }
 
-    Ok(JsWord::from(ident))
+    JsWord::from(ident)
+}
+
+/// Splits the `segment_dir` option into the directory segments and entries are emitted in, and
+/// the prefix of their file names: `build/q-` emits `build/q-<name>.js` files. A value without `/`
+/// is a directory, `./q-` sets a prefix alone.
+pub fn split_segment_dir(segment_dir: Option<&str>) -> (&str, &str) {
+    segment_dir.map_or(("", ""), |segment_dir| match segment_dir.rsplit_once('/') {
+        Some((".", prefix)) => ("", prefix),
+        Some((dir, prefix)) => (dir.strip_prefix("./").unwrap_or(dir), prefix),
+        None => (segment_dir, ""),
+    })
+}
+
+/// Path of `file_name` in the directory returned by `split_segment_dir`.
+pub fn join_segment_dir(segment_dir: &str, file_name: &str) -> String {
+    if segment_dir.is_empty() {
+        file_name.to_string()
+    } else {
+        [segment_dir, "/", file_name].concat()
+    }
 }
 
 fn create_named_export(expr: Box<ast::Expr>, name: &str) -> ast::ModuleItem {
@@ -209,56 +236,70 @@ fn create_named_export(expr: Box<ast::Expr>, name: &str) -> ast::ModuleItem {
 #[test]
 fn test_fix_path() {
     assert_eq!(
-        fix_path("src", "", "./state.qwik.mjs").unwrap(),
+        fix_path("src", "", "./state.qwik.mjs"),
         JsWord::from("./src/state.qwik.mjs")
     );
 
     assert_eq!(
-        fix_path("src/path", "", "./state").unwrap(),
+        fix_path("src/path", "", "./state"),
         JsWord::from("./src/path/state")
     );
 
-    assert_eq!(
-        fix_path("src", "", "../state").unwrap(),
-        JsWord::from("./state")
-    );
-    assert_eq!(
-        fix_path("a", "a", "./state").unwrap(),
-        JsWord::from("./state")
-    );
+    assert_eq!(fix_path("src", "", "../state"), JsWord::from("./state"));
+    assert_eq!(fix_path("a", "a", "./state"), JsWord::from("./state"));
 }
 
 pub fn generate_entries(
     mut output: TransformOutput,
     core_module: &JsWord,
     explicit_extensions: bool,
-    root_dir: Option<&Path>,
-) -> Result<TransformOutput, anyhow::Error> {
+    source_maps: bool,
+    size_limits: Option<&SizeLimits>,
+    segment_dir: Option<&str>,
+) -> Result<TransformOutput, TransformError> {
+    let (segment_dir, prefix) = split_segment_dir(segment_dir);
+    if let Some(size_limits) = size_limits {
+        merge_segments_by_size(&mut output, size_limits, prefix);
+    }
     let source_map = Lrc::new(SourceMap::default());
-    let mut entries_map: BTreeMap<&str, Vec<&HookAnalysis>> = BTreeMap::new();
+    let mut entries_map: BTreeMap<&str, Vec<&TransformModule>> = BTreeMap::new();
     let mut new_modules = Vec::with_capacity(output.modules.len());
     {
-        let hooks: Vec<&HookAnalysis> = output.modules.iter().flat_map(|m| &m.hook).collect();
-        for hook in hooks {
-            if let Some(ref e) = hook.entry {
+        for module in &output.modules {
+            if let Some(e) = module.hook.as_ref().and_then(|hook| hook.entry.as_ref()) {
                 entries_map
                     .entry(e.as_ref())
                     .or_insert_with(Vec::new)
-                    .push(hook);
+                    .push(module);
             }
         }
 
-        for (entry, hooks) in &entries_map {
-            let module = new_entry_module(hooks, core_module, explicit_extensions);
-            let (code, map) =
-                emit_source_code(Lrc::clone(&source_map), None, &module, root_dir, false)
-                    .context("Emitting source code")?;
+        for (entry, modules) in &entries_map {
+            let path = join_segment_dir(segment_dir, &[prefix, entry, ".js"].concat());
+            let hooks: Vec<&HookAnalysis> = modules
+                .iter()
+                .filter_map(|module| module.hook.as_ref())
+                .collect();
+            let module = new_entry_module(&hooks, core_module, explicit_extensions);
+            let (code, _) = emit_source_code(
+                Lrc::clone(&source_map),
+                None,
+                &module,
+                &EmitOptions::default(),
+            )?;
+            let map = if source_maps {
+                entry_source_map(&path, modules)
+            } else {
+                None
+            };
             new_modules.push(TransformModule {
-                path: [entry, ".js"].concat(),
+                path,
                 code,
                 map,
+                imports: collect_imports(&module),
                 is_entry: true,
                 hook: None,
+                inlined_hooks: vec![],
                 order: 0,
             });
         }
@@ -268,6 +309,437 @@ pub fn generate_entries(
     Ok(output)
 }
 
+/// Merges the segments without entry of every origin into entries of at least
+/// `limits.min_size` bytes, without going over `limits.max_size`, and points the imports of the
+/// merged segments to their entry, named with `prefix`. Segments that end up alone keep their own
+/// file.
+fn merge_segments_by_size(output: &mut TransformOutput, limits: &SizeLimits, prefix: &str) {
+    let mut origins: BTreeMap<&str, Vec<(usize, &HookAnalysis)>> = BTreeMap::new();
+    for (index, module) in output.modules.iter().enumerate() {
+        if let Some(hook) = module.hook.as_ref().filter(|hook| hook.entry.is_none()) {
+            origins
+                .entry(hook.origin.as_ref())
+                .or_default()
+                .push((index, hook));
+        }
+    }
+    let mut groups: Vec<(Vec<usize>, usize)> = vec![];
+    for hooks in origins.values_mut() {
+        // Segments of the same component share the prefix of their display name
+        hooks.sort_by(|a, b| a.1.display_name.cmp(&b.1.display_name));
+        let origin_start = groups.len();
+        let mut group = vec![];
+        let mut size = 0;
+        for (index, _) in hooks.iter() {
+            let len = output.modules[*index].code.len();
+            if !group.is_empty() && (size >= limits.min_size || size + len > limits.max_size) {
+                groups.push((std::mem::take(&mut group), size));
+                size = 0;
+            }
+            group.push(*index);
+            size += len;
+        }
+        let has_previous = groups.len() > origin_start;
+        match groups.last_mut() {
+            // A remainder under the minimum joins the previous group of the origin if it fits
+            Some((previous, previous_size))
+                if has_previous
+                    && size < limits.min_size
+                    && *previous_size + size <= limits.max_size =>
+            {
+                previous.append(&mut group);
+                *previous_size += size;
+            }
+            _ => groups.push((group, size)),
+        }
+    }
+
+    let mut entries: HashMap<String, JsWord> = HashMap::new();
+    for (group, _) in groups.iter().filter(|(group, _)| group.len() > 1) {
+        let entry = match &output.modules[group[0]].hook {
+            Some(hook) => JsWord::from(["entry_", &hook.hash].concat()),
+            None => continue,
+        };
+        for index in group {
+            let module = &mut output.modules[*index];
+            module.is_entry = false;
+            if let Some(hook) = &mut module.hook {
+                entries.insert(
+                    hook.canonical_filename.to_string(),
+                    JsWord::from([prefix, &entry].concat()),
+                );
+                hook.entry = Some(entry.clone());
+            }
+        }
+    }
+    if entries.is_empty() {
+        return;
+    }
+    for module in &mut output.modules {
+        rename_imports(module, &entries, true);
+    }
+}
+
+/// Renames the segments and entries of `output` to `[name].[contenthash].js`, and rewrites every
+/// import of them, so they can be cached forever.
+///
+/// The hash of a file covers its own code and the code of every file it imports, directly or not,
+/// so changing a segment also renames the files pointing to it, while files importing each other
+/// get a hash over the same code.
+pub fn hash_file_names(output: &mut TransformOutput) {
+    let renamed: HashMap<String, usize> = output
+        .modules
+        .iter()
+        .enumerate()
+        .filter(|(_, module)| module.hook.is_some() || module.is_entry)
+        .map(|(index, module)| (file_stem(&module.path).to_string(), index))
+        .collect();
+    let code_hashes: Vec<u64> = output
+        .modules
+        .iter()
+        .map(|module| {
+            let mut hasher = stable_hasher();
+            hasher.write(module.code.as_bytes());
+            hasher.finish()
+        })
+        .collect();
+    let imports: Vec<Vec<usize>> = output
+        .modules
+        .iter()
+        .map(|module| {
+            module
+                .imports
+                .iter()
+                .filter_map(|import| renamed.get(file_stem(&import.specifier)).copied())
+                .collect()
+        })
+        .collect();
+
+    let mut renames: HashMap<String, JsWord> = HashMap::with_capacity(renamed.len());
+    for (stem, index) in &renamed {
+        let mut reached = HashSet::from([*index]);
+        let mut pending = vec![*index];
+        while let Some(current) = pending.pop() {
+            for import in &imports[current] {
+                if reached.insert(*import) {
+                    pending.push(*import);
+                }
+            }
+        }
+        let mut hashes: Vec<u64> = reached
+            .iter()
+            .filter(|reached| *reached != index)
+            .map(|reached| code_hashes[*reached])
+            .collect();
+        hashes.sort_unstable();
+        let mut hasher = stable_hasher();
+        hasher.write_u64(code_hashes[*index]);
+        for hash in hashes {
+            hasher.write_u64(hash);
+        }
+        renames.insert(
+            stem.clone(),
+            JsWord::from(format!("{}.{:08x}", stem, hasher.finish() >> 32)),
+        );
+    }
+
+    for module in &mut output.modules {
+        rename_imports(module, &renames, false);
+        if module.hook.is_none() && !module.is_entry {
+            continue;
+        }
+        let stem = file_stem(&module.path);
+        if let Some(new_stem) = renames.get(stem) {
+            let start =
+                module.path.len() - module.path.rsplit('/').next().unwrap_or_default().len();
+            module.path = [
+                &module.path[..start],
+                new_stem.as_ref(),
+                &module.path[start + stem.len()..],
+            ]
+            .concat();
+            // The manifest maps symbols to `canonical_filename.extension`
+            if let Some(hook) = &mut module.hook {
+                hook.canonical_filename = new_stem.clone();
+            }
+            for hook in &mut module.inlined_hooks {
+                hook.canonical_filename = new_stem.clone();
+            }
+        }
+    }
+}
+
+/// Name of the file at `path` without its directory nor its extensions.
+fn file_stem(path: &str) -> &str {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    file_name.split('.').next().unwrap_or(file_name)
+}
+
+/// Collects the imports of `module`, in order.
+pub fn collect_imports(module: &ast::Module) -> Vec<ModuleImport> {
+    collect_import_spans(module)
+        .into_iter()
+        .map(|(import, _)| import)
+        .collect()
+}
+
+/// Collects the imports of `module` along with the span of their specifier: import declarations,
+/// re-exports and `import()` calls with a string specifier. Imports of types only are skipped, as
+/// they are erased.
+fn collect_import_spans(module: &ast::Module) -> Vec<(ModuleImport, Span)> {
+    let mut collector = ImportCollector { imports: vec![] };
+    module.visit_with(&mut collector);
+    collector.imports
+}
+
+struct ImportCollector {
+    imports: Vec<(ModuleImport, Span)>,
+}
+
+impl ImportCollector {
+    fn add(&mut self, specifier: &ast::Str, dynamic: bool) {
+        self.imports.push((
+            ModuleImport {
+                specifier: specifier.value.clone(),
+                dynamic,
+            },
+            specifier.span,
+        ));
+    }
+}
+
+impl Visit for ImportCollector {
+    noop_visit_type!();
+
+    fn visit_import_decl(&mut self, node: &ast::ImportDecl) {
+        if !node.type_only {
+            self.add(&node.src, false);
+        }
+    }
+
+    fn visit_named_export(&mut self, node: &ast::NamedExport) {
+        if let Some(src) = node.src.as_deref().filter(|_| !node.type_only) {
+            self.add(src, false);
+        }
+    }
+
+    fn visit_export_all(&mut self, node: &ast::ExportAll) {
+        self.add(&node.src, false);
+    }
+
+    fn visit_call_expr(&mut self, node: &ast::CallExpr) {
+        let is_import = match &node.callee {
+            ast::Callee::Import(_) => true,
+            // The QRLs built by the transform call `import` as an identifier
+            ast::Callee::Expr(callee) => {
+                matches!(&**callee, ast::Expr::Ident(ident) if &*ident.sym == "import")
+            }
+            ast::Callee::Super(_) => false,
+        };
+        if is_import {
+            if let Some(ast::ExprOrSpread { spread: None, expr }) = node.args.first() {
+                if let ast::Expr::Lit(ast::Lit::Str(specifier)) = &**expr {
+                    self.add(specifier, true);
+                }
+            }
+        }
+        node.visit_children_with(self);
+    }
+}
+
+/// Returns the specifier importing the renamed file when the file stem of `specifier` is in
+/// `renames`.
+fn rename_specifier(specifier: &str, renames: &HashMap<String, JsWord>) -> Option<String> {
+    let file_name = specifier.rsplit('/').next().unwrap_or(specifier);
+    let stem = file_stem(file_name);
+    renames.get(stem).map(|renamed| {
+        [
+            &specifier[..specifier.len() - file_name.len()],
+            renamed.as_ref(),
+            &file_name[stem.len()..],
+        ]
+        .concat()
+    })
+}
+
+/// Replaces the file name of the imports of `module` whose file stem is in `renames`, only the
+/// `import()` calls when `dynamic_only` is set.
+///
+/// Segments and entries are all emitted next to each other, so only the file name changes, and
+/// the mappings following every replaced specifier on its line are shifted accordingly.
+fn rename_imports(
+    module: &mut TransformModule,
+    renames: &HashMap<String, JsWord>,
+    dynamic_only: bool,
+) {
+    let is_renamed = |import: &ModuleImport| {
+        (import.dynamic || !dynamic_only) && rename_specifier(&import.specifier, renames).is_some()
+    };
+    if !module.imports.iter().any(is_renamed) {
+        return;
+    }
+    let code = &module.code;
+    let specifiers = match parse_import_specifiers(&module.path, code) {
+        Some(specifiers) => specifiers,
+        None => return,
+    };
+    let mut output = String::with_capacity(code.len());
+    let mut shifts: Vec<(u32, u32, i64)> = vec![];
+    let mut last = 0;
+    for (import, start, end) in specifiers {
+        let specifier = &code[start..end];
+        let replacement = match rename_specifier(specifier, renames) {
+            Some(replacement) if import.dynamic || !dynamic_only => replacement,
+            _ => continue,
+        };
+        let line_start = code[..end]
+            .rfind('\n')
+            .map_or(0, |line_start| line_start + 1);
+        shifts.push((
+            code[..line_start].matches('\n').count() as u32,
+            code[line_start..end].encode_utf16().count() as u32,
+            replacement.len() as i64 - specifier.len() as i64,
+        ));
+        output.push_str(&code[last..start]);
+        output.push_str(&replacement);
+        last = end;
+    }
+    if shifts.is_empty() {
+        return;
+    }
+    output.push_str(&code[last..]);
+    module.map = module
+        .map
+        .as_deref()
+        .map(|map| shift_source_map(map, &shifts).unwrap_or_else(|| map.to_string()));
+    module.code = output;
+    for import in &mut module.imports {
+        match rename_specifier(&import.specifier, renames) {
+            Some(replacement) if import.dynamic || !dynamic_only => {
+                import.specifier = replacement.into();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Parses the emitted `code` of the module at `path` back, and returns its imports along with the
+/// range of their specifier in `code`. The spans of the AST the module was emitted from point to
+/// its sources, not to `code`.
+fn parse_import_specifiers(path: &str, code: &str) -> Option<Vec<(ModuleImport, usize, usize)>> {
+    let source_map = SourceMap::default();
+    let source_file = source_map.new_source_file(FileName::Anon, code.to_string());
+    let module = parse_output(path, &source_file)?;
+    let offset = |pos: BytePos| (pos.0 - source_file.start_pos.0) as usize;
+    Some(
+        collect_import_spans(&module)
+            .into_iter()
+            // The span of a string covers its quotes
+            .map(|(import, span)| (import, offset(span.lo) + 1, offset(span.hi) - 1))
+            .collect(),
+    )
+}
+
+/// Parses the emitted code of the module at `path`, registered as `source_file`.
+fn parse_output(path: &str, source_file: &SourceFile) -> Option<ast::Module> {
+    let syntax = match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("ts" | "mts") => Syntax::Typescript(TsConfig {
+            decorators: true,
+            ..Default::default()
+        }),
+        Some("tsx" | "mtsx") => Syntax::Typescript(TsConfig {
+            tsx: true,
+            decorators: true,
+            ..Default::default()
+        }),
+        _ => Syntax::Es(EsConfig {
+            jsx: true,
+            ..Default::default()
+        }),
+    };
+    let lexer = Lexer::new(
+        syntax,
+        Default::default(),
+        StringInput::from(source_file),
+        None,
+    );
+    Parser::new_from(lexer).parse_module().ok()
+}
+
+/// Moves the generated columns of the mappings after each `(line, column, delta)` shift.
+fn shift_source_map(map: &str, shifts: &[(u32, u32, i64)]) -> Option<String> {
+    let map = sourcemap::SourceMap::from_slice(map.as_bytes()).ok()?;
+    let mut builder = sourcemap::SourceMapBuilder::new(map.get_file());
+    for (index, source) in map.sources().enumerate() {
+        let source_id = builder.add_source(source);
+        builder.set_source_contents(source_id, map.get_source_contents(index as u32));
+    }
+    for token in map.tokens() {
+        let (line, column) = (token.get_dst_line(), token.get_dst_col());
+        let delta: i64 = shifts
+            .iter()
+            .filter(|shift| shift.0 == line && shift.1 <= column)
+            .map(|shift| shift.2)
+            .sum();
+        builder.add(
+            line,
+            (i64::from(column) + delta) as u32,
+            token.get_src_line(),
+            token.get_src_col(),
+            token.get_source(),
+            token.get_name(),
+        );
+    }
+    let mut buffer = vec![];
+    builder.into_sourcemap().to_writer(&mut buffer).ok()?;
+    String::from_utf8(buffer).ok()
+}
+
+/// Builds the source map of the entry at `entry_path`, which re-exports the symbol of each of
+/// its `segments` on its own line, in order. Every line maps to the declaration of the symbol in
+/// the segment file, named by its path relative to the entry.
+fn entry_source_map(entry_path: &str, segments: &[&TransformModule]) -> Option<String> {
+    let entry_dir = Path::new(entry_path)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    let mut builder = sourcemap::SourceMapBuilder::new(None);
+    for (line, module) in segments.iter().enumerate() {
+        let position = module
+            .hook
+            .as_ref()
+            .and_then(|hook| export_position(module, &hook.name));
+        if let Some((src_line, src_col)) = position {
+            let path = pathdiff::diff_paths(&module.path, entry_dir)
+                .unwrap_or_else(|| PathBuf::from(&module.path));
+            let source = path.to_slash_lossy();
+            builder.add(line as u32, 0, src_line, src_col, Some(&*source), None);
+        }
+    }
+    let mut buffer = vec![];
+    builder.into_sourcemap().to_writer(&mut buffer).ok()?;
+    String::from_utf8(buffer).ok()
+}
+
+/// Returns the line and the column of the declaration exporting `symbol` in the code of `module`,
+/// found in the AST parsed back from the code.
+fn export_position(module: &TransformModule, symbol: &str) -> Option<(u32, u32)> {
+    let source_map = SourceMap::default();
+    let source_file = source_map.new_source_file(FileName::Anon, module.code.clone());
+    let segment = parse_output(&module.path, &source_file)?;
+    let span = segment.body.iter().find_map(|item| match item {
+        ast::ModuleItem::ModuleDecl(ast::ModuleDecl::ExportDecl(ast::ExportDecl {
+            decl: ast::Decl::Var(var),
+            ..
+        })) => var.decls.iter().find_map(|decl| match &decl.name {
+            ast::Pat::Ident(ident) if &*ident.id.sym == symbol => Some(ident.id.span),
+            _ => None,
+        }),
+        _ => None,
+    })?;
+    let loc = source_map.lookup_char_pos(span.lo);
+    Some(((loc.line - 1) as u32, loc.col.0 as u32))
+}
+
 fn new_entry_module(
     hooks: &[&HookAnalysis],
     core_module: &JsWord,
@@ -1,8 +1,11 @@
+use crate::errors::TransformError;
+use crate::parse::QwikManifest;
 use crate::transform::HookData;
+use crate::utils::glob_match;
 use crate::words::*;
 use crate::{parse::PathData, transform::HookKind};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use swc_atoms::JsWord;
 
 use lazy_static::lazy_static;
@@ -13,7 +16,7 @@ lazy_static! {
 }
 
 // EntryStrategies
-#[derive(Debug, Serialize, Copy, Clone, Deserialize)]
+#[derive(Debug, Serialize, Copy, Clone, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub enum EntryStrategy {
     Inline,
@@ -21,10 +24,231 @@ pub enum EntryStrategy {
     Single,
     Hook,
     Component,
+    #[default]
     Smart,
+    /// Assigns entries with the `EntryRules` of the options
+    Rules,
+    /// Groups the segments requested together according to the `UsageProfile` of the options
+    Profile,
+    /// Merges the emitted segments of every origin into entries within the `SizeLimits` of the
+    /// options
+    Size,
 }
 
+/// Ordered list of rules mapping segments to named entries, the first matching rule wins.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct EntryRules {
+    pub rules: Vec<EntryRule>,
+    /// Strategy used for the segments no rule matches, it can not inline nor hoist them
+    #[serde(default)]
+    pub fallback: EntryStrategy,
+}
+
+impl EntryRules {
+    /// Rejects the fallbacks that can not be applied to single segments, inlining and hoisting
+    /// change the whole transform and not only the entry of the segments.
+    pub fn validate(&self) -> Result<(), TransformError> {
+        match self.fallback {
+            EntryStrategy::Inline | EntryStrategy::Hoist => {
+                Err(TransformError::InvalidOptions(format!(
+                    "the fallback of the entry rules can not be {:?}",
+                    self.fallback
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Matches the segments meeting every condition that is set.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EntryRule {
+    pub entry: JsWord,
+    /// Glob of the path of the file that declares the segment, relative to the source directory
+    pub origin: Option<String>,
+    pub ctx_name: Option<JsWord>,
+    pub ctx_kind: Option<HookKind>,
+    /// Glob of the display name of the segment, e.g. `Header_component_*`
+    pub display_name: Option<String>,
+    /// Glob of the name of the top level component that contains the segment
+    pub component: Option<String>,
+}
+
+impl EntryRule {
+    fn matches(&self, context: &[String], hook_data: &HookData) -> bool {
+        self.origin
+            .as_ref()
+            .map_or(true, |origin| glob_match(origin, &hook_data.origin))
+            && self
+                .ctx_name
+                .as_ref()
+                .map_or(true, |ctx_name| ctx_name == &hook_data.ctx_name)
+            && self
+                .ctx_kind
+                .map_or(true, |ctx_kind| ctx_kind == hook_data.ctx_kind)
+            && self.display_name.as_ref().map_or(true, |display_name| {
+                glob_match(display_name, &hook_data.display_name)
+            })
+            && self.component.as_ref().map_or(true, |component| {
+                context
+                    .first()
+                    .map_or(false, |root| glob_match(component, root))
+            })
+    }
+}
+
+/// Sizes of the entries produced by `EntryStrategy::Size`, in bytes of emitted code.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SizeLimits {
+    /// Segments are merged until their entry reaches this size
+    pub min_size: usize,
+    /// Segments are never merged into entries over this size
+    pub max_size: usize,
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        Self {
+            min_size: 4 * 1024,
+            max_size: 50 * 1024,
+        }
+    }
+}
+
+/// Symbols requested together by real sessions of the application.
+///
+/// Symbols can be given either as their hash or as their full symbol name, e.g. `s_abc123` or
+/// `Header_component_abc123`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageProfile {
+    /// Traces of the symbols requested by every recorded session
+    #[serde(default)]
+    pub sessions: Vec<Vec<String>>,
+    /// Number of sessions that requested both symbols, for profiles aggregated ahead of time
+    #[serde(default)]
+    pub co_usage: Vec<SymbolCoUsage>,
+    /// Number of sessions that requested each symbol, for profiles aggregated ahead of time
+    #[serde(default)]
+    pub usage: HashMap<String, u32>,
+    /// Fraction of the sessions of the least used symbol of a pair that must also request the
+    /// other one for both to be bundled together
+    #[serde(default = "default_affinity")]
+    pub affinity: f64,
+    /// Pairs requested together by fewer sessions are never bundled together
+    #[serde(default = "default_min_sessions")]
+    pub min_sessions: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SymbolCoUsage {
+    pub symbols: (String, String),
+    pub count: u32,
+}
+
+const fn default_affinity() -> f64 {
+    0.5
+}
+
+const fn default_min_sessions() -> u32 {
+    2
+}
+
+impl Default for UsageProfile {
+    fn default() -> Self {
+        Self {
+            sessions: vec![],
+            co_usage: vec![],
+            usage: HashMap::new(),
+            affinity: default_affinity(),
+            min_sessions: default_min_sessions(),
+        }
+    }
+}
+
+impl UsageProfile {
+    /// Clusters the symbols that are usually requested together, and names the entry of every
+    /// cluster after its smallest hash so it does not change when unrelated symbols come and go.
+    /// Symbols that are not clustered with any other are left out.
+    fn clusters(&self) -> HashMap<String, JsWord> {
+        let mut usage: HashMap<&str, u32> = HashMap::new();
+        let mut pairs: HashMap<(&str, &str), u32> = HashMap::new();
+        for (symbol, count) in &self.usage {
+            *usage.entry(symbol_hash(symbol)).or_default() += count;
+        }
+        for co_usage in &self.co_usage {
+            let (a, b) = (
+                symbol_hash(&co_usage.symbols.0),
+                symbol_hash(&co_usage.symbols.1),
+            );
+            if a != b {
+                *pairs.entry((a.min(b), a.max(b))).or_default() += co_usage.count;
+            }
+        }
+        for session in &self.sessions {
+            let mut symbols: Vec<&str> = session.iter().map(|s| symbol_hash(s)).collect();
+            symbols.sort_unstable();
+            symbols.dedup();
+            for (index, a) in symbols.iter().enumerate() {
+                *usage.entry(*a).or_default() += 1;
+                for b in &symbols[index + 1..] {
+                    *pairs.entry((*a, *b)).or_default() += 1;
+                }
+            }
+        }
+
+        let mut parents: HashMap<&str, &str> = HashMap::new();
+        for (&(a, b), &count) in &pairs {
+            let least_used = [a, b]
+                .iter()
+                .map(|symbol| usage.get(symbol).copied().unwrap_or(count).max(count))
+                .min()
+                .unwrap_or(count);
+            if count >= self.min_sessions
+                && f64::from(count) >= self.affinity * f64::from(least_used)
+            {
+                let (root_a, root_b) = (find_root(&parents, a), find_root(&parents, b));
+                if root_a != root_b {
+                    parents.insert(root_a.max(root_b), root_a.min(root_b));
+                }
+            }
+        }
+        let symbols: HashSet<&str> = parents.iter().flat_map(|(a, b)| [*a, *b]).collect();
+        symbols
+            .into_iter()
+            .map(|symbol| {
+                let root = find_root(&parents, symbol);
+                (symbol.to_string(), JsWord::from(["entry_", root].concat()))
+            })
+            .collect()
+    }
+}
+
+/// Hashes never contain `_`, so the hash of a symbol name is what follows its last `_`.
+fn symbol_hash(symbol: &str) -> &str {
+    symbol.rsplit('_').next().unwrap_or(symbol)
+}
+
+/// Roots are always the smallest symbol of their cluster.
+fn find_root<'a>(parents: &HashMap<&'a str, &'a str>, mut symbol: &'a str) -> &'a str {
+    while let Some(parent) = parents.get(symbol) {
+        symbol = parent;
+    }
+    symbol
+}
+
+/// Decides which entry every segment is bundled in.
+///
+/// Implement it to supply a custom grouping through `TransformModulesOptions::entry_policy`, the
+/// built-in strategies are available from `parse_entry_strategy`.
 pub trait EntryPolicy: Send + Sync {
+    /// Returns the entry of the segment with symbol hash `hash`, or `None` to emit it in its own
+    /// file. `context` is the stack of component, function and element names the segment was
+    /// declared in, outermost first.
     fn get_entry_for_sym(
         &self,
         hash: &str,
@@ -178,15 +402,158 @@ impl EntryPolicy for SmartStrategy {
     }
 }
 
+pub struct RulesStrategy {
+    map: Option<HashMap<String, JsWord>>,
+    rules: Vec<EntryRule>,
+    fallback: Box<dyn EntryPolicy>,
+}
+
+impl RulesStrategy {
+    pub fn new(map: Option<HashMap<String, JsWord>>, rules: EntryRules) -> Self {
+        let fallback = match rules.fallback {
+            // Fallbacks are applied during the transform, without rules, profile or sizes
+            EntryStrategy::Rules | EntryStrategy::Profile | EntryStrategy::Size => {
+                EntryStrategy::Smart
+            }
+            fallback => fallback,
+        };
+        Self {
+            fallback: parse_entry_strategy(&fallback, None, None, None),
+            rules: rules.rules,
+            map,
+        }
+    }
+}
+
+impl EntryPolicy for RulesStrategy {
+    fn get_entry_for_sym(
+        &self,
+        hash: &str,
+        path: &PathData,
+        context: &[String],
+        hook_data: &HookData,
+    ) -> Option<JsWord> {
+        if let Some(map) = &self.map {
+            let entry = map.get(hash);
+            if let Some(entry) = entry {
+                return Some(entry.clone());
+            }
+        }
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(context, hook_data))
+            .map_or_else(
+                || {
+                    self.fallback
+                        .get_entry_for_sym(hash, path, context, hook_data)
+                },
+                |rule| Some(rule.entry.clone()),
+            )
+    }
+}
+
+pub struct ProfileStrategy {
+    map: Option<HashMap<String, JsWord>>,
+    clusters: HashMap<String, JsWord>,
+}
+
+impl ProfileStrategy {
+    pub fn new(map: Option<HashMap<String, JsWord>>, profile: &UsageProfile) -> Self {
+        Self {
+            map,
+            clusters: profile.clusters(),
+        }
+    }
+}
+
+impl EntryPolicy for ProfileStrategy {
+    fn get_entry_for_sym(
+        &self,
+        hash: &str,
+        _path: &PathData,
+        _context: &[String],
+        _hook_data: &HookData,
+    ) -> Option<JsWord> {
+        if let Some(map) = &self.map {
+            let entry = map.get(hash);
+            if let Some(entry) = entry {
+                return Some(entry.clone());
+            }
+        }
+        // Cold segments, and the ones missing from the profile, get their own file
+        self.clusters.get(hash).cloned()
+    }
+}
+
+/// Keeps the segments of a previous build in the entry they had, so the bundles of unchanged code
+/// keep their content and stay cached, only new segments are assigned by `policy`.
+///
+/// Segments listed in the manual chunks are still assigned by `policy`, so they can be moved.
+pub struct StableStrategy<'a> {
+    previous: HashMap<String, Option<JsWord>>,
+    manual_chunks: Option<&'a HashMap<String, JsWord>>,
+    policy: &'a dyn EntryPolicy,
+}
+
+impl<'a> StableStrategy<'a> {
+    pub fn new(
+        previous_manifest: &QwikManifest,
+        manual_chunks: Option<&'a HashMap<String, JsWord>>,
+        policy: &'a dyn EntryPolicy,
+    ) -> Self {
+        Self {
+            previous: previous_manifest
+                .symbols
+                .values()
+                .map(|hook| (hook.hash.to_string(), hook.entry.clone()))
+                .collect(),
+            manual_chunks,
+            policy,
+        }
+    }
+}
+
+impl EntryPolicy for StableStrategy<'_> {
+    fn get_entry_for_sym(
+        &self,
+        hash: &str,
+        path: &PathData,
+        context: &[String],
+        hook_data: &HookData,
+    ) -> Option<JsWord> {
+        let is_manual = self
+            .manual_chunks
+            .map_or(false, |manual_chunks| manual_chunks.contains_key(hash));
+        match self.previous.get(hash) {
+            Some(entry) if !is_manual => entry.clone(),
+            _ => self
+                .policy
+                .get_entry_for_sym(hash, path, context, hook_data),
+        }
+    }
+}
+
+/// Builds the policy of a built-in `EntryStrategy`, `manual_chunks` takes precedence over it.
 pub fn parse_entry_strategy(
     strategy: &EntryStrategy,
     manual_chunks: Option<HashMap<String, JsWord>>,
+    entry_rules: Option<EntryRules>,
+    usage_profile: Option<UsageProfile>,
 ) -> Box<dyn EntryPolicy> {
     match strategy {
         EntryStrategy::Inline | EntryStrategy::Hoist => Box::new(InlineStrategy::default()),
-        EntryStrategy::Hook => Box::new(PerHookStrategy::new(manual_chunks)),
+        // Size merges the segments once they are emitted
+        EntryStrategy::Hook | EntryStrategy::Size => Box::new(PerHookStrategy::new(manual_chunks)),
         EntryStrategy::Single => Box::new(SingleStrategy::new(manual_chunks)),
         EntryStrategy::Component => Box::new(PerComponentStrategy::new(manual_chunks)),
         EntryStrategy::Smart => Box::new(SmartStrategy::new(manual_chunks)),
+        EntryStrategy::Rules => Box::new(RulesStrategy::new(
+            manual_chunks,
+            entry_rules.unwrap_or_default(),
+        )),
+        EntryStrategy::Profile => Box::new(ProfileStrategy::new(
+            manual_chunks,
+            &usage_profile.unwrap_or_default(),
+        )),
     }
 }
@@ -1,3 +1,5 @@
+use std::fmt;
+
 use swc_common::errors::DiagnosticId;
 
 pub enum Error {
@@ -5,9 +7,59 @@ pub enum Error {
     CanNotCapture,
     DynamicImportInsideQhook,
     MissingQrlImplementation,
+    HashCollision,
+    InvalidInlinedQrl,
+    InvalidPath,
+    ReadFailed,
+    EmitFailed,
+    UnusedSuppression,
+    NonSerializableCapture,
 }
 
-pub fn get_diagnostic_id(err: Error) -> DiagnosticId {
+pub fn get_diagnostic_code(err: Error) -> String {
     let id = err as u32;
-    DiagnosticId::Error(format!("C{:02}", id))
+    format!("C{:02}", id)
+}
+
+pub fn get_diagnostic_id(err: Error) -> DiagnosticId {
+    DiagnosticId::Error(get_diagnostic_code(err))
+}
+
+/// Error of the optimizer. When it concerns a single file, it is reported as a diagnostic of the
+/// file instead, so the other files of the batch are still transformed.
+#[derive(Debug)]
+pub enum TransformError {
+    /// The options can not be used
+    InvalidOptions(String),
+    /// The path of a file can not be used as a module path, like a path without extension
+    InvalidPath(String),
+    /// Reading an input file or directory failed
+    Io(String),
+    /// Generating the code of a module failed
+    Emit(String),
+}
+
+impl TransformError {
+    /// Code of the diagnostics reporting this error.
+    pub fn diagnostic_code(&self) -> Option<String> {
+        match self {
+            Self::InvalidOptions(_) => None,
+            Self::InvalidPath(_) => Some(get_diagnostic_code(Error::InvalidPath)),
+            Self::Io(_) => Some(get_diagnostic_code(Error::ReadFailed)),
+            Self::Emit(_) => Some(get_diagnostic_code(Error::EmitFailed)),
+        }
+    }
 }
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidOptions(message) => write!(f, "Invalid options: {}", message),
+            Self::InvalidPath(message) => write!(f, "Invalid path: {}", message),
+            Self::Io(message) => write!(f, "Reading failed: {}", message),
+            Self::Emit(message) => write!(f, "Emitting code failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for TransformError {}
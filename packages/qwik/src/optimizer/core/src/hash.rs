@@ -0,0 +1,66 @@
+//! Stable hashing of symbol names.
+//!
+//! Symbol hashes end up in QRLs stored by applications and in the URLs of segments cached by CDNs,
+//! so they must not change between builds of the optimizer. `DefaultHasher` is explicitly not
+//! guaranteed to be stable across Rust releases, so hashes are computed with SipHash-1-3 keyed
+//! with zeros, which is what `DefaultHasher::new()` currently does, keeping existing names intact.
+//!
+//! Changing the algorithm renames every symbol, `HASH_ALGORITHM` must be bumped when it happens,
+//! along with its copy in `manifest.ts` that the plugins write in their manifest.
+
+use crate::errors;
+use crate::parse::TransformOutput;
+use crate::utils::{Diagnostic, DiagnosticCategory, DiagnosticScope};
+
+use std::collections::HashMap;
+
+use siphasher::sip::SipHasher13;
+use swc_atoms::JsWord;
+
+/// Algorithm used to compute symbol hashes, recorded in the manifest.
+pub const HASH_ALGORITHM: &str = "siphash13-v1";
+
+/// Algorithm of the manifests written before it was recorded, hashes were computed with
+/// `DefaultHasher::new()`.
+pub const LEGACY_HASH_ALGORITHM: &str = "default-hasher";
+
+pub fn legacy_hash_algorithm() -> JsWord {
+    LEGACY_HASH_ALGORITHM.into()
+}
+
+pub type StableHasher = SipHasher13;
+
+pub fn stable_hasher() -> StableHasher {
+    SipHasher13::new_with_keys(0, 0)
+}
+
+/// Reports the segments whose hash is shared with a different segment, their symbols would
+/// collide at runtime.
+pub fn find_hash_collisions(output: &TransformOutput) -> Vec<Diagnostic> {
+    let mut seen = HashMap::new();
+    let mut diagnostics = vec![];
+    for hook in output
+        .modules
+        .iter()
+        .filter_map(|module| module.hook.as_ref())
+    {
+        let first = seen.entry(&hook.hash).or_insert(hook);
+        if first.origin != hook.origin || first.display_name != hook.display_name {
+            diagnostics.push(Diagnostic {
+                category: DiagnosticCategory::Error,
+                code: Some(errors::get_diagnostic_code(errors::Error::HashCollision)),
+                file: hook.origin.clone(),
+                message: format!(
+                    "Segment `{}` in {} has the same hash `{}` as segment `{}` in {}",
+                    hook.display_name, hook.origin, hook.hash, first.display_name, first.origin
+                ),
+                highlights: None,
+                suggestions: Some(vec![
+                    "Rename one of the segments, or set a different `scope`".into(),
+                ]),
+                scope: DiagnosticScope::Optimizer,
+            });
+        }
+    }
+    diagnostics
+}
@@ -0,0 +1,283 @@
+//! Heuristics finding the variables that likely hold values a QRL can not serialize, like sockets,
+//! DOM nodes, promises or functions. They are reported as warnings when a segment captures them,
+//! since serializing them fails at runtime.
+
+use swc_ecmascript::ast;
+
+/// Methods returning DOM nodes
+const DOM_QUERIES: &[&str] = &[
+    "getElementById",
+    "getElementsByClassName",
+    "getElementsByTagName",
+    "querySelector",
+    "querySelectorAll",
+    "createElement",
+    "createTextNode",
+    "elementFromPoint",
+    "closest",
+];
+
+/// Functions taking a callback that do not return a function
+const TIMERS: &[&str] = &[
+    "setTimeout",
+    "setInterval",
+    "requestAnimationFrame",
+    "requestIdleCallback",
+    "queueMicrotask",
+];
+
+/// Classes of the browser whose instances hold resources, and can not be serialized
+const RESOURCE_CLASSES: &[&str] = &[
+    "WebSocket",
+    "Worker",
+    "SharedWorker",
+    "MessagePort",
+    "MessageChannel",
+    "BroadcastChannel",
+    "EventSource",
+    "AbortController",
+    "ReadableStream",
+    "WritableStream",
+    "MutationObserver",
+    "IntersectionObserver",
+    "ResizeObserver",
+];
+
+/// Collects the bindings of `pat` that likely hold values that are not serializable, from the value
+/// `init` assigned to the pattern, the default values and the type annotations of its bindings.
+pub fn non_serializable_bindings<'a>(
+    pat: &'a ast::Pat,
+    init: Option<&'a ast::Expr>,
+    ts_type: Option<&'a ast::TsType>,
+    bindings: &mut Vec<(&'a ast::Ident, String)>,
+) {
+    match pat {
+        ast::Pat::Ident(binding) => {
+            // Type annotations are only left when TypeScript is not transpiled
+            let description = init.and_then(non_serializable_value).or_else(|| {
+                binding
+                    .type_ann
+                    .as_deref()
+                    .map(|type_ann| &*type_ann.type_ann)
+                    .or(ts_type)
+                    .and_then(non_serializable_type)
+            });
+            if let Some(description) = description {
+                bindings.push((&binding.id, description));
+            }
+        }
+        // Default values, like `(signal = new AbortController()) => {}`
+        ast::Pat::Assign(assign) => {
+            non_serializable_bindings(
+                &assign.left,
+                init.or(Some(&*assign.right)),
+                ts_type,
+                bindings,
+            );
+        }
+        ast::Pat::Object(object) => {
+            let ts_type = object
+                .type_ann
+                .as_deref()
+                .map(|type_ann| &*type_ann.type_ann)
+                .or(ts_type);
+            for prop in &object.props {
+                match prop {
+                    ast::ObjectPatProp::KeyValue(key_value) => {
+                        let key = prop_name(&key_value.key);
+                        non_serializable_bindings(
+                            &key_value.value,
+                            key.and_then(|key| property_value(init, key)),
+                            key.and_then(|key| property_type(ts_type, key)),
+                            bindings,
+                        );
+                    }
+                    ast::ObjectPatProp::Assign(assign) => {
+                        let description = property_value(init, &assign.key.sym)
+                            .or(assign.value.as_deref())
+                            .and_then(non_serializable_value)
+                            .or_else(|| {
+                                property_type(ts_type, &assign.key.sym)
+                                    .and_then(non_serializable_type)
+                            });
+                        if let Some(description) = description {
+                            bindings.push((&assign.key, description));
+                        }
+                    }
+                    ast::ObjectPatProp::Rest(_) => {}
+                }
+            }
+        }
+        ast::Pat::Array(array) => {
+            for (index, elem) in array.elems.iter().enumerate() {
+                if let Some(elem) = elem {
+                    non_serializable_bindings(elem, element_value(init, index), None, bindings);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Describes the value of the initializer `init` when it is likely not serializable, like
+/// `a Promise`.
+pub fn non_serializable_value(init: &ast::Expr) -> Option<String> {
+    match init {
+        ast::Expr::Paren(paren) => non_serializable_value(&paren.expr),
+        // Like for annotations, only known classes are flagged, user classes may be serializable
+        ast::Expr::New(new) => match &*new.callee {
+            ast::Expr::Ident(ident) => non_serializable_type_name(&ident.sym),
+            _ => None,
+        },
+        ast::Expr::Call(call) => match &call.callee {
+            ast::Callee::Expr(callee) => non_serializable_call(callee, &call.args),
+            _ => None,
+        },
+        ast::Expr::Ident(ident) if matches!(&*ident.sym, "document" | "window") => {
+            Some("a DOM node".into())
+        }
+        ast::Expr::Member(ast::MemberExpr {
+            obj,
+            prop: ast::MemberProp::Ident(prop),
+            ..
+        }) if is_ident(obj, "document")
+            && matches!(
+                &*prop.sym,
+                "body" | "head" | "documentElement" | "activeElement"
+            ) =>
+        {
+            Some("a DOM node".into())
+        }
+        _ => None,
+    }
+}
+
+fn non_serializable_call(callee: &ast::Expr, args: &[ast::ExprOrSpread]) -> Option<String> {
+    match callee {
+        ast::Expr::Ident(ident) if &*ident.sym == "fetch" => Some("a Promise".into()),
+        // Wrapping a callback, like `debounce(() => {})`, most likely returns a function
+        ast::Expr::Ident(ident) => {
+            let name: &str = &ident.sym;
+            let wraps_function = args
+                .iter()
+                .any(|arg| matches!(&*arg.expr, ast::Expr::Arrow(_) | ast::Expr::Fn(_)));
+            (wraps_function
+                && !name.ends_with('$')
+                && !name.starts_with("use")
+                && !TIMERS.contains(&name))
+            .then(|| format!("the function returned by {}()", name))
+        }
+        ast::Expr::Member(member) => {
+            let prop: &str = match &member.prop {
+                ast::MemberProp::Ident(prop) => &prop.sym,
+                _ => return None,
+            };
+            if is_ident(&member.obj, "Promise") || matches!(prop, "then" | "catch" | "finally") {
+                Some("a Promise".into())
+            } else if DOM_QUERIES.contains(&prop) {
+                Some("a DOM node".into())
+            } else if prop == "bind" {
+                Some("a bound function".into())
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Describes the values of the type annotation `ts_type` when they are likely not serializable.
+pub fn non_serializable_type(ts_type: &ast::TsType) -> Option<String> {
+    match ts_type {
+        ast::TsType::TsFnOrConstructorType(_) => Some("a function".into()),
+        ast::TsType::TsTypeRef(type_ref) => match &type_ref.type_name {
+            ast::TsEntityName::Ident(ident) => non_serializable_type_name(&ident.sym),
+            ast::TsEntityName::TsQualifiedName(_) => None,
+        },
+        ast::TsType::TsParenthesizedType(paren) => non_serializable_type(&paren.type_ann),
+        // Like `HTMLElement | undefined`
+        ast::TsType::TsUnionOrIntersectionType(ast::TsUnionOrIntersectionType::TsUnionType(
+            union,
+        )) => union
+            .types
+            .iter()
+            .find_map(|ts_type| non_serializable_type(ts_type)),
+        _ => None,
+    }
+}
+
+fn non_serializable_type_name(name: &str) -> Option<String> {
+    match name {
+        "Promise" => Some("a Promise".into()),
+        "Function" => Some("a function".into()),
+        "Node" | "Element" | "Document" | "Window" | "EventTarget" => Some("a DOM node".into()),
+        _ if (name.starts_with("HTML") || name.starts_with("SVG")) && name.ends_with("Element") => {
+            Some("a DOM node".into())
+        }
+        _ if RESOURCE_CLASSES.contains(&name) => Some(format!("an instance of {}", name)),
+        _ => None,
+    }
+}
+
+fn prop_name(prop_name: &ast::PropName) -> Option<&str> {
+    match prop_name {
+        ast::PropName::Ident(ident) => Some(&*ident.sym),
+        ast::PropName::Str(string) => Some(&*string.value),
+        _ => None,
+    }
+}
+
+/// Value of the property `key` when `init` is an object literal.
+fn property_value<'a>(init: Option<&'a ast::Expr>, key: &str) -> Option<&'a ast::Expr> {
+    match init? {
+        ast::Expr::Object(object) => object.props.iter().rev().find_map(|prop| match prop {
+            ast::PropOrSpread::Prop(prop) => match &**prop {
+                ast::Prop::KeyValue(key_value) if prop_name(&key_value.key) == Some(key) => {
+                    Some(&*key_value.value)
+                }
+                _ => None,
+            },
+            ast::PropOrSpread::Spread(_) => None,
+        }),
+        _ => None,
+    }
+}
+
+/// Element at `index` when `init` is an array literal.
+fn element_value(init: Option<&ast::Expr>, index: usize) -> Option<&ast::Expr> {
+    match init? {
+        ast::Expr::Array(array) => {
+            let elems = array.elems.get(..=index)?;
+            // Spread elements move the ones after them
+            if elems.iter().flatten().any(|elem| elem.spread.is_some()) {
+                return None;
+            }
+            elems[index].as_ref().map(|elem| &*elem.expr)
+        }
+        _ => None,
+    }
+}
+
+/// Type of the property `key` when `ts_type` is a type literal, like `{ ws: WebSocket }`.
+fn property_type<'a>(ts_type: Option<&'a ast::TsType>, key: &str) -> Option<&'a ast::TsType> {
+    match ts_type? {
+        ast::TsType::TsTypeLit(type_lit) => {
+            type_lit.members.iter().find_map(|member| match member {
+                ast::TsTypeElement::TsPropertySignature(signature)
+                    if !signature.computed && is_ident(&signature.key, key) =>
+                {
+                    signature
+                        .type_ann
+                        .as_deref()
+                        .map(|type_ann| &*type_ann.type_ann)
+                }
+                _ => None,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn is_ident(expr: &ast::Expr, name: &str) -> bool {
+    matches!(expr, ast::Expr::Ident(ident) if &*ident.sym == name)
+}
@@ -10,6 +10,7 @@
 mod test;
 
 mod add_side_effect;
+mod cache;
 mod clean_side_effects;
 mod code_move;
 mod collector;
@@ -18,11 +19,14 @@ mod entry_strategy;
 mod errors;
 mod filter_exports;
 mod has_branches;
+mod hash;
 mod inlined_fn;
 mod is_immutable;
+mod is_serializable;
 mod package_json;
 mod parse;
 mod props_destructuring;
+mod suppress;
 mod transform;
 mod utils;
 mod words;
@@ -30,12 +34,13 @@ mod words;
 #[cfg(feature = "parallel")]
 use rayon::prelude::*;
 
-#[cfg(feature = "parallel")]
-use anyhow::Context;
+use derivative::Derivative;
 use words::BUILDER_IO_QWIK;
 
 #[cfg(feature = "fs")]
 use std::fs;
+#[cfg(feature = "fs")]
+use std::path::PathBuf;
 
 use anyhow::Error;
 use serde::{Deserialize, Serialize};
@@ -44,25 +49,71 @@ use std::path::Path;
 use std::str;
 use swc_atoms::JsWord;
 
-use crate::code_move::generate_entries;
-use crate::entry_strategy::parse_entry_strategy;
-pub use crate::entry_strategy::EntryStrategy;
-pub use crate::parse::EmitMode;
+use crate::cache::options_fingerprint;
+pub use crate::cache::TransformCache;
+use crate::code_move::{generate_entries, hash_file_names};
+pub use crate::collector::Id;
+pub use crate::entry_strategy::parse_entry_strategy;
+pub use crate::entry_strategy::{
+    EntryPolicy, EntryRule, EntryRules, EntryStrategy, SizeLimits, StableStrategy, SymbolCoUsage,
+    UsageProfile,
+};
+pub use crate::errors::TransformError;
+use crate::hash::find_hash_collisions;
+#[cfg(feature = "fs")]
+pub use crate::package_json::find_modules;
+#[cfg(feature = "fs")]
+use crate::parse::find_source_mapping_url;
 use crate::parse::{transform_code, TransformCodeOptions};
-pub use crate::parse::{ErrorBuffer, HookAnalysis, MinifyMode, TransformModule, TransformOutput};
+pub use crate::parse::{EmitMode, EsTarget, PathData, SourceMapsOption};
+pub use crate::parse::{
+    ErrorBuffer, HookAnalysis, MinifyMode, ModuleImport, TransformModule, TransformOutput,
+};
+pub use crate::parse::{ManifestDiff, MovedSymbol, QwikBundle, QwikManifest};
+pub use crate::transform::{HookData, HookKind};
+pub use crate::utils::{Diagnostic, DiagnosticCategory, DiagnosticScope, SourceLocation};
 
 #[cfg(feature = "fs")]
-#[derive(Serialize, Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[derive(Serialize, Derivative, Deserialize)]
+#[derivative(Debug)]
+#[serde(rename_all = "camelCase", default)]
 pub struct TransformFsOptions {
     pub src_dir: String,
     pub root_dir: Option<String>,
     pub vendor_roots: Vec<String>,
     pub glob: Option<String>,
     pub minify: MinifyMode,
+    pub target: EsTarget,
     pub entry_strategy: EntryStrategy,
+    /// Custom policy assigning segments to entries, used instead of `entry_strategy`. The output
+    /// of a custom policy can not be fingerprinted, so it is never cached.
+    #[serde(skip)]
+    #[derivative(Debug = "ignore")]
+    pub entry_policy: Option<Box<dyn EntryPolicy>>,
     pub manual_chunks: Option<HashMap<String, JsWord>>,
+    pub entry_rules: Option<EntryRules>,
+    pub usage_profile: Option<UsageProfile>,
+    pub size_limits: Option<SizeLimits>,
+    /// Segments whose code is smaller than this many bytes are inlined in their parent module
+    pub inline_threshold: Option<usize>,
+    /// Manifest of a previous build, its segments keep their entry and the output reports what
+    /// changed since
+    pub previous_manifest: Option<QwikManifest>,
+    /// Emits segments and entries as `[name].[contenthash].js`, rewriting every import of them
+    pub content_hash: bool,
+    /// Base URL the QRLs import segments from, like `https://cdn.example.com/build/`, with the
+    /// extension of the emitted files. QRLs import them relatively to the importing module by
+    /// default
+    pub qrl_base: Option<String>,
+    /// Directory segments and entries are emitted in, relative to the root of the output, with
+    /// the prefix of their file names: `build/q-` emits `build/q-<name>.js` files. A value without
+    /// `/` is a directory, `./q-` sets a prefix alone
+    pub segment_dir: Option<String>,
+    /// Keeps transforming files the parser only found recoverable syntax errors in, their
+    /// diagnostics are still reported
+    pub recover_parse_errors: bool,
     pub source_maps: bool,
+    pub sources_content: bool,
     pub transpile_ts: bool,
     pub transpile_jsx: bool,
     pub preserve_filenames: bool,
@@ -78,26 +129,109 @@ pub struct TransformFsOptions {
     pub is_server: Option<bool>,
 }
 
+#[cfg(feature = "fs")]
+impl Default for TransformFsOptions {
+    fn default() -> Self {
+        Self {
+            src_dir: ".".into(),
+            root_dir: None,
+            vendor_roots: vec![],
+            glob: None,
+            minify: MinifyMode::default(),
+            target: EsTarget::default(),
+            entry_strategy: EntryStrategy::default(),
+            entry_policy: None,
+            manual_chunks: None,
+            entry_rules: None,
+            usage_profile: None,
+            size_limits: None,
+            inline_threshold: None,
+            previous_manifest: None,
+            content_hash: false,
+            qrl_base: None,
+            segment_dir: None,
+            recover_parse_errors: false,
+            source_maps: false,
+            sources_content: false,
+            transpile_ts: true,
+            transpile_jsx: true,
+            preserve_filenames: false,
+            explicit_extensions: false,
+            mode: EmitMode::default(),
+            scope: None,
+            core_module: None,
+            strip_exports: None,
+            strip_ctx_name: None,
+            strip_event_handlers: false,
+            reg_ctx_name: None,
+            is_server: None,
+        }
+    }
+}
+
 #[derive(Serialize, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TransformModuleInput {
     pub path: String,
     pub code: String,
+    /// Source map of `code`, used to map the output to the original sources. When missing, a map
+    /// inlined in a `sourceMappingURL` comment of the code is used instead.
+    #[serde(default)]
+    pub input_source_map: Option<String>,
+    /// The module is third-party code, its errors are reported as `SourceError` so they do not fail
+    /// the build. Modules in `node_modules` always are.
+    #[serde(default)]
+    pub vendored: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Serialize, Derivative, Deserialize)]
+#[derivative(Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct TransformModulesOptions {
     pub src_dir: String,
     pub root_dir: Option<String>,
     pub input: Vec<TransformModuleInput>,
     pub source_maps: bool,
+    #[serde(default)]
+    pub sources_content: bool,
     pub minify: MinifyMode,
+    #[serde(default)]
+    pub target: EsTarget,
     pub transpile_ts: bool,
     pub transpile_jsx: bool,
     pub preserve_filenames: bool,
     pub entry_strategy: EntryStrategy,
+    /// Custom policy assigning segments to entries, used instead of `entry_strategy`. The output
+    /// of a custom policy can not be fingerprinted, so it is never cached.
+    #[serde(skip)]
+    #[derivative(Debug = "ignore")]
+    pub entry_policy: Option<Box<dyn EntryPolicy>>,
     pub manual_chunks: Option<HashMap<String, JsWord>>,
+    pub entry_rules: Option<EntryRules>,
+    pub usage_profile: Option<UsageProfile>,
+    pub size_limits: Option<SizeLimits>,
+    /// Segments whose code is smaller than this many bytes are inlined in their parent module
+    pub inline_threshold: Option<usize>,
+    /// Manifest of a previous build, its segments keep their entry and the output reports what
+    /// changed since
+    pub previous_manifest: Option<QwikManifest>,
+    /// Emits segments and entries as `[name].[contenthash].js`, rewriting every import of them
+    #[serde(default)]
+    pub content_hash: bool,
+    /// Base URL the QRLs import segments from, like `https://cdn.example.com/build/`, with the
+    /// extension of the emitted files. QRLs import them relatively to the importing module by
+    /// default
+    #[serde(default)]
+    pub qrl_base: Option<String>,
+    /// Directory segments and entries are emitted in, relative to the root of the output, with
+    /// the prefix of their file names: `build/q-` emits `build/q-<name>.js` files. A value without
+    /// `/` is a directory, `./q-` sets a prefix alone
+    #[serde(default)]
+    pub segment_dir: Option<String>,
+    /// Keeps transforming files the parser only found recoverable syntax errors in, their
+    /// diagnostics are still reported
+    #[serde(default)]
+    pub recover_parse_errors: bool,
     pub explicit_extensions: bool,
     pub mode: EmitMode,
     pub scope: Option<String>,
@@ -111,16 +245,124 @@ pub struct TransformModulesOptions {
 }
 
 #[cfg(feature = "fs")]
-pub fn transform_fs(config: TransformFsOptions) -> Result<TransformOutput, Error> {
+pub fn transform_fs(config: TransformFsOptions) -> Result<TransformOutput, TransformError> {
+    transform_fs_inner(&config, None)
+}
+
+/// Same as `transform_fs`, but reuses the output of files whose content and options did not change
+/// since they were stored in `cache`.
+#[cfg(feature = "fs")]
+pub fn transform_fs_with_cache(
+    config: &TransformFsOptions,
+    cache: &TransformCache,
+) -> Result<TransformOutput, TransformError> {
+    transform_fs_inner(config, Some(cache))
+}
+
+#[cfg(feature = "fs")]
+fn transform_fs_inner(
+    config: &TransformFsOptions,
+    cache: Option<&TransformCache>,
+) -> Result<TransformOutput, TransformError> {
+    let fingerprint = options_fingerprint(config)
+        .map_err(|err| TransformError::InvalidOptions(err.to_string()))?;
+    if let Some(entry_rules) = &config.entry_rules {
+        entry_rules.validate()?;
+    }
     let core_module = config
         .core_module
-        .map_or(BUILDER_IO_QWIK.clone(), |s| s.into());
+        .as_ref()
+        .map_or(BUILDER_IO_QWIK.clone(), |s| s.as_str().into());
     let src_dir = Path::new(&config.src_dir);
     let root_dir = config.root_dir.as_ref().map(Path::new);
 
     let mut paths = vec![];
-    let entry_policy = &*parse_entry_strategy(&config.entry_strategy, config.manual_chunks);
-    crate::package_json::find_modules(src_dir, config.vendor_roots, &mut paths)?;
+    let size_limits = matches!(config.entry_strategy, EntryStrategy::Size)
+        .then_some(config.size_limits.unwrap_or_default());
+    let default_policy;
+    let (entry_policy, cache) = match &config.entry_policy {
+        Some(entry_policy) => (&**entry_policy, None),
+        None => {
+            default_policy = parse_entry_strategy(
+                &config.entry_strategy,
+                config.manual_chunks.clone(),
+                config.entry_rules.clone(),
+                config.usage_profile.clone(),
+            );
+            (&*default_policy, cache)
+        }
+    };
+    let stable_policy;
+    let entry_policy = match &config.previous_manifest {
+        Some(previous_manifest) => {
+            stable_policy = StableStrategy::new(
+                previous_manifest,
+                config.manual_chunks.as_ref(),
+                entry_policy,
+            );
+            &stable_policy as &dyn EntryPolicy
+        }
+        None => entry_policy,
+    };
+    crate::package_json::find_modules(src_dir, config.vendor_roots.clone(), &mut paths)
+        .map_err(|err| TransformError::Io(format!("{}: {}", src_dir.display(), err)))?;
+
+    let transform_file = |path: &PathBuf| -> Result<TransformOutput, Error> {
+        let code = fs::read_to_string(path)
+            .map_err(|err| TransformError::Io(format!("{}: {}", path.display(), err)))?;
+
+        let relative_path = pathdiff::diff_paths(path, &config.src_dir).ok_or_else(|| {
+            TransformError::InvalidPath(format!(
+                "{} is not relative to {}",
+                path.display(),
+                config.src_dir
+            ))
+        })?;
+        let input_source_map = if config.source_maps {
+            read_input_source_map(path, &code)
+        } else {
+            None
+        };
+        let options = TransformCodeOptions {
+            src_dir,
+            root_dir,
+            vendored: config
+                .vendor_roots
+                .iter()
+                .any(|vendor_root| path.starts_with(vendor_root)),
+            relative_path: relative_path.to_str().ok_or_else(|| {
+                TransformError::InvalidPath(format!("{} is not UTF-8", path.display()))
+            })?,
+            minify: config.minify,
+            target: config.target,
+            code: &code,
+            explicit_extensions: config.explicit_extensions,
+            source_maps: config.source_maps,
+            sources_content: config.sources_content,
+            input_source_map: input_source_map.as_deref(),
+            transpile_jsx: config.transpile_jsx,
+            transpile_ts: config.transpile_ts,
+            preserve_filenames: config.preserve_filenames,
+            scope: config.scope.as_ref(),
+            entry_policy,
+            mode: config.mode,
+            core_module: core_module.clone(),
+            entry_strategy: config.entry_strategy,
+            inline_threshold: config.inline_threshold,
+            qrl_base: config.qrl_base.as_deref(),
+            segment_dir: config.segment_dir.as_deref(),
+            recover_parse_errors: config.recover_parse_errors,
+            reg_ctx_name: config.reg_ctx_name.as_deref(),
+            strip_exports: config.strip_exports.as_deref(),
+            strip_ctx_name: config.strip_ctx_name.as_deref(),
+            strip_event_handlers: config.strip_event_handlers,
+            is_server: config.is_server,
+        };
+        match cache {
+            Some(cache) => cache.get_or_transform(fingerprint, options),
+            None => transform_code(options),
+        }
+    };
 
     #[cfg(feature = "parallel")]
     let iterator = paths.par_iter();
@@ -128,46 +370,25 @@ pub fn transform_fs(config: TransformFsOptions) -> Result<TransformOutput, Error
     #[cfg(not(feature = "parallel"))]
     let iterator = paths.iter();
     let mut final_output = iterator
-        .map(|path| -> Result<TransformOutput, Error> {
-            let code = fs::read_to_string(path)
-                .with_context(|| format!("Opening {}", &path.to_string_lossy()))?;
-
-            let relative_path = pathdiff::diff_paths(path, &config.src_dir).unwrap();
-            transform_code(TransformCodeOptions {
-                src_dir,
-                root_dir,
-                relative_path: relative_path.to_str().unwrap(),
-                minify: config.minify,
-                code: &code,
-                explicit_extensions: config.explicit_extensions,
-                source_maps: config.source_maps,
-                transpile_jsx: config.transpile_jsx,
-                transpile_ts: config.transpile_ts,
-                preserve_filenames: config.preserve_filenames,
-                scope: config.scope.as_ref(),
-                entry_policy,
-                mode: config.mode,
-                core_module: core_module.clone(),
-                entry_strategy: config.entry_strategy,
-                reg_ctx_name: config.reg_ctx_name.as_deref(),
-                strip_exports: config.strip_exports.as_deref(),
-                strip_ctx_name: config.strip_ctx_name.as_deref(),
-                strip_event_handlers: config.strip_event_handlers,
-                is_server: config.is_server,
+        .map(|path| {
+            transform_file(path).unwrap_or_else(|error| {
+                let file = path.strip_prefix(src_dir).unwrap_or(path);
+                file_error_output(&file.to_string_lossy(), &error)
             })
         })
-        .reduce(|| Ok(TransformOutput::new()), |x, y| Ok(x?.append(&mut y?)))?;
+        .reduce(TransformOutput::new, |x, mut y| x.append(&mut y));
 
     final_output.modules.sort_unstable_by_key(|key| key.order);
-    if !matches!(
-        config.entry_strategy,
-        EntryStrategy::Hook | EntryStrategy::Inline | EntryStrategy::Hoist
-    ) {
+    let mut collisions = find_hash_collisions(&final_output);
+    final_output.diagnostics.append(&mut collisions);
+    if needs_entries(&final_output, config.entry_strategy) {
         final_output = generate_entries(
             final_output,
             &core_module,
             config.explicit_extensions,
-            root_dir,
+            config.source_maps,
+            size_limits.as_ref(),
+            config.segment_dir.as_deref(),
         )?;
     }
     // final_output = generate_entries(
@@ -176,30 +397,134 @@ pub fn transform_fs(config: TransformFsOptions) -> Result<TransformOutput, Error
     //     config.explicit_extensions,
     //     root_dir,
     // )?;
+    if let Some(previous_manifest) = &config.previous_manifest {
+        final_output.manifest_diff = Some(final_output.diff_manifest(previous_manifest));
+    }
+    if config.content_hash {
+        hash_file_names(&mut final_output);
+    }
     Ok(final_output)
 }
 
-pub fn transform_modules(config: TransformModulesOptions) -> Result<TransformOutput, Error> {
+/// Entries are generated for the strategies grouping segments, and for any other strategy as soon
+/// as a segment was assigned an entry, by a custom policy or a previous manifest.
+fn needs_entries(output: &TransformOutput, entry_strategy: EntryStrategy) -> bool {
+    !matches!(
+        entry_strategy,
+        EntryStrategy::Hook | EntryStrategy::Inline | EntryStrategy::Hoist
+    ) || output.modules.iter().any(|module| {
+        module
+            .hook
+            .as_ref()
+            .map_or(false, |hook| hook.entry.is_some())
+    })
+}
+
+/// Output of a file that failed to transform, reporting `error` as a diagnostic of the file.
+fn file_error_output(file: &str, error: &Error) -> TransformOutput {
+    TransformOutput {
+        diagnostics: vec![Diagnostic {
+            category: DiagnosticCategory::Error,
+            code: error
+                .downcast_ref::<TransformError>()
+                .and_then(TransformError::diagnostic_code),
+            file: file.into(),
+            message: format!("{:#}", error),
+            highlights: None,
+            suggestions: None,
+            scope: DiagnosticScope::Optimizer,
+        }],
+        ..TransformOutput::new()
+    }
+}
+
+/// Reads the external source map referenced by the `sourceMappingURL` comment of `code`, maps
+/// inlined as data URLs are handled by `transform_code`.
+#[cfg(feature = "fs")]
+fn read_input_source_map(path: &Path, code: &str) -> Option<String> {
+    let url = find_source_mapping_url(code)?;
+    if url.starts_with("data:") {
+        return None;
+    }
+    fs::read_to_string(path.parent()?.join(url)).ok()
+}
+
+pub fn transform_modules(
+    config: TransformModulesOptions,
+) -> Result<TransformOutput, TransformError> {
+    transform_modules_inner(config, None)
+}
+
+/// Same as `transform_modules`, but reuses the output of inputs whose content and options did not
+/// change since they were stored in `cache`.
+pub fn transform_modules_with_cache(
+    config: TransformModulesOptions,
+    cache: &TransformCache,
+) -> Result<TransformOutput, TransformError> {
+    transform_modules_inner(config, Some(cache))
+}
+
+fn transform_modules_inner(
+    mut config: TransformModulesOptions,
+    cache: Option<&TransformCache>,
+) -> Result<TransformOutput, TransformError> {
+    let input = std::mem::take(&mut config.input);
+    let custom_policy = config.entry_policy.take();
+    let fingerprint = options_fingerprint(&config)
+        .map_err(|err| TransformError::InvalidOptions(err.to_string()))?;
+    if let Some(entry_rules) = &config.entry_rules {
+        entry_rules.validate()?;
+    }
     let core_module = config
         .core_module
         .map_or(BUILDER_IO_QWIK.clone(), |s| s.into());
     let src_dir = std::path::Path::new(&config.src_dir);
     let root_dir = config.root_dir.as_ref().map(Path::new);
 
-    let entry_policy = &*parse_entry_strategy(&config.entry_strategy, config.manual_chunks);
+    let size_limits = matches!(config.entry_strategy, EntryStrategy::Size)
+        .then_some(config.size_limits.unwrap_or_default());
+    let default_policy;
+    let (entry_policy, cache) = match &custom_policy {
+        Some(entry_policy) => (&**entry_policy, None),
+        None => {
+            default_policy = parse_entry_strategy(
+                &config.entry_strategy,
+                config.manual_chunks.clone(),
+                config.entry_rules,
+                config.usage_profile,
+            );
+            (&*default_policy, cache)
+        }
+    };
+    let stable_policy;
+    let entry_policy = match &config.previous_manifest {
+        Some(previous_manifest) => {
+            stable_policy = StableStrategy::new(
+                previous_manifest,
+                config.manual_chunks.as_ref(),
+                entry_policy,
+            );
+            &stable_policy as &dyn EntryPolicy
+        }
+        None => entry_policy,
+    };
     #[cfg(feature = "parallel")]
-    let iterator = config.input.par_iter();
+    let iterator = input.par_iter();
 
     #[cfg(not(feature = "parallel"))]
-    let iterator = config.input.iter();
-    let iterator = iterator.map(|path| -> Result<TransformOutput, Error> {
-        transform_code(TransformCodeOptions {
+    let iterator = input.iter();
+    let iterator = iterator.map(|path| {
+        let options = TransformCodeOptions {
             src_dir,
             root_dir,
+            vendored: path.vendored,
             relative_path: &path.path,
             code: &path.code,
             minify: config.minify,
+            target: config.target,
             source_maps: config.source_maps,
+            sources_content: config.sources_content,
+            input_source_map: path.input_source_map.as_deref(),
             transpile_ts: config.transpile_ts,
             transpile_jsx: config.transpile_jsx,
             preserve_filenames: config.preserve_filenames,
@@ -209,33 +534,40 @@ pub fn transform_modules(config: TransformModulesOptions) -> Result<TransformOut
             scope: config.scope.as_ref(),
             core_module: core_module.clone(),
             entry_strategy: config.entry_strategy,
+            inline_threshold: config.inline_threshold,
+            qrl_base: config.qrl_base.as_deref(),
+            segment_dir: config.segment_dir.as_deref(),
+            recover_parse_errors: config.recover_parse_errors,
             reg_ctx_name: config.reg_ctx_name.as_deref(),
             strip_exports: config.strip_exports.as_deref(),
             strip_ctx_name: config.strip_ctx_name.as_deref(),
             strip_event_handlers: config.strip_event_handlers,
             is_server: config.is_server,
-        })
+        };
+        match cache {
+            Some(cache) => cache.get_or_transform(fingerprint, options),
+            None => transform_code(options),
+        }
+        .unwrap_or_else(|error| file_error_output(&path.path, &error))
     });
 
     #[cfg(feature = "parallel")]
-    let final_output: Result<TransformOutput, Error> =
-        iterator.reduce(|| Ok(TransformOutput::new()), |x, y| Ok(x?.append(&mut y?)));
+    let mut final_output = iterator.reduce(TransformOutput::new, |x, mut y| x.append(&mut y));
 
     #[cfg(not(feature = "parallel"))]
-    let final_output: Result<TransformOutput, Error> =
-        iterator.fold(Ok(TransformOutput::new()), |x, y| Ok(x?.append(&mut y?)));
+    let mut final_output = iterator.fold(TransformOutput::new(), |x, mut y| x.append(&mut y));
 
-    let mut final_output = final_output?;
     final_output.modules.sort_unstable_by_key(|key| key.order);
-    if !matches!(
-        config.entry_strategy,
-        EntryStrategy::Hook | EntryStrategy::Inline | EntryStrategy::Hoist
-    ) {
+    let mut collisions = find_hash_collisions(&final_output);
+    final_output.diagnostics.append(&mut collisions);
+    if needs_entries(&final_output, config.entry_strategy) {
         final_output = generate_entries(
             final_output,
             &core_module,
             config.explicit_extensions,
-            root_dir,
+            config.source_maps,
+            size_limits.as_ref(),
+            config.segment_dir.as_deref(),
         )?;
     }
     // final_output = generate_entries(
@@ -244,6 +576,12 @@ pub fn transform_modules(config: TransformModulesOptions) -> Result<TransformOut
     //     config.explicit_extensions,
     //     root_dir,
     // )?;
+    if let Some(previous_manifest) = &config.previous_manifest {
+        final_output.manifest_diff = Some(final_output.diff_manifest(previous_manifest));
+    }
+    if config.content_hash {
+        hash_file_names(&mut final_output);
+    }
 
     Ok(final_output)
 }
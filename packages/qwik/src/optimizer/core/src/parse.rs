@@ -1,5 +1,4 @@
-use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::hash::Hasher;
 use std::path::{Component, Path, PathBuf};
@@ -7,33 +6,51 @@ use std::str;
 
 use crate::add_side_effect::SideEffectVisitor;
 use crate::clean_side_effects::Treeshaker;
-use crate::code_move::{new_module, NewModuleCtx};
+use crate::code_move::{
+    collect_imports, join_segment_dir, new_module, split_segment_dir, NewModuleCtx,
+};
 use crate::collector::global_collect;
 use crate::const_replace::ConstReplacerVisitor;
 use crate::entry_strategy::EntryPolicy;
+use crate::errors::TransformError;
 use crate::filter_exports::StripExportsVisitor;
+use crate::hash::{legacy_hash_algorithm, stable_hasher, HASH_ALGORITHM};
 use crate::props_destructuring::transform_props_destructuring;
-use crate::transform::{HookKind, QwikTransform, QwikTransformOptions};
+use crate::suppress::{apply_suppressions, collect_suppressions};
+use crate::transform::{Hook, HookKind, InlinedHook, QwikTransform, QwikTransformOptions};
 use crate::utils::{Diagnostic, DiagnosticCategory, DiagnosticScope, SourceLocation};
 use crate::EntryStrategy;
 use path_slash::PathExt;
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "fs")]
+use anyhow::Context;
 #[cfg(feature = "fs")]
 use std::fs;
 
-use anyhow::{Context, Error};
+use anyhow::Error;
+use base64::Engine;
 
 use swc_atoms::JsWord;
 use swc_common::comments::SingleThreadedComments;
-use swc_common::errors::{DiagnosticBuilder, DiagnosticId, Emitter, Handler};
+use swc_common::errors::{DiagnosticBuilder, DiagnosticId, Emitter, Handler, Level};
+use swc_common::source_map::SourceMapGenConfig;
 use swc_common::{sync::Lrc, FileName, Globals, Mark, SourceMap};
 use swc_ecmascript::ast;
 use swc_ecmascript::codegen::text_writer::JsWriter;
+use swc_ecmascript::minifier::{
+    self,
+    option::{CompressOptions, ExtraOptions, MangleOptions, MinifyOptions},
+};
+use swc_ecmascript::parser::error::Error as ParserError;
 use swc_ecmascript::parser::lexer::Lexer;
 use swc_ecmascript::parser::{EsConfig, PResult, Parser, StringInput, Syntax, TsConfig};
 use swc_ecmascript::transforms::{
-    fixer, hygiene::hygiene_with_config, optimization::simplify, react, resolver, typescript,
+    compat, fixer,
+    helpers::{inject_helpers, Helpers, HELPERS},
+    hygiene::hygiene_with_config,
+    optimization::simplify,
+    react, resolver, typescript,
 };
 use swc_ecmascript::visit::{FoldWith, VisitMutWith};
 
@@ -54,27 +71,76 @@ pub struct HookAnalysis {
     pub loc: (u32, u32),
 }
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Default)]
 #[serde(rename_all = "camelCase")]
 pub enum MinifyMode {
+    /// Compresses and mangles every module, and emits minified code
+    Minify,
+    #[default]
     Simplify,
     None,
 }
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+/// ECMAScript version of the output, the syntax of newer editions is lowered with SWC's compat
+/// transforms.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EsTarget {
+    Es2017,
+    Es2018,
+    Es2019,
+    Es2020,
+    Es2021,
+    Es2022,
+    #[default]
+    EsNext,
+}
+
+impl EsTarget {
+    const fn es_version(self) -> ast::EsVersion {
+        match self {
+            Self::Es2017 => ast::EsVersion::Es2017,
+            Self::Es2018 => ast::EsVersion::Es2018,
+            Self::Es2019 => ast::EsVersion::Es2019,
+            Self::Es2020 => ast::EsVersion::Es2020,
+            Self::Es2021 => ast::EsVersion::Es2021,
+            Self::Es2022 => ast::EsVersion::Es2022,
+            Self::EsNext => ast::EsVersion::EsNext,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Default)]
 #[serde(rename_all = "camelCase")]
 pub enum EmitMode {
     Prod,
+    #[default]
     Lib,
     Dev,
 }
 
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SourceMapsOption {
+    /// Writes the source map to a `.map` file next to the module
+    #[default]
+    External,
+    /// Embeds the source map in the module as a base64 data URL
+    Inline,
+}
+
 pub struct TransformCodeOptions<'a> {
     pub relative_path: &'a str,
     pub src_dir: &'a Path,
     pub root_dir: Option<&'a Path>,
+    /// The module is third-party code, like the files of the vendor roots, its errors are reported
+    /// as `SourceError` so they do not fail the build. Modules in `node_modules` always are.
+    pub vendored: bool,
     pub source_maps: bool,
+    pub sources_content: bool,
+    pub input_source_map: Option<&'a str>,
     pub minify: MinifyMode,
+    pub target: EsTarget,
     pub transpile_ts: bool,
     pub transpile_jsx: bool,
     pub preserve_filenames: bool,
@@ -84,6 +150,11 @@ pub struct TransformCodeOptions<'a> {
     pub mode: EmitMode,
     pub scope: Option<&'a String>,
     pub entry_strategy: EntryStrategy,
+    pub inline_threshold: Option<usize>,
+    pub qrl_base: Option<&'a str>,
+    pub segment_dir: Option<&'a str>,
+    /// Keeps transforming the module when the parser only found recoverable syntax errors
+    pub recover_parse_errors: bool,
     pub core_module: JsWord,
 
     pub reg_ctx_name: Option<&'a [JsWord]>,
@@ -93,13 +164,16 @@ pub struct TransformCodeOptions<'a> {
     pub is_server: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct TransformOutput {
     pub modules: Vec<TransformModule>,
     pub diagnostics: Vec<Diagnostic>,
     pub is_type_script: bool,
     pub is_jsx: bool,
+    /// Changes since the `previous_manifest` of the options, when one is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest_diff: Option<ManifestDiff>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -107,15 +181,48 @@ pub struct TransformOutput {
 pub struct QwikBundle {
     pub size: usize,
     pub symbols: Vec<JsWord>,
+    /// Bundles imported statically, like the parent module of a segment or the members of an entry
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub imports: Vec<JsWord>,
+    /// Bundles imported by the QRLs of this bundle
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dynamic_imports: Vec<JsWord>,
+    /// Source files of the symbols of this bundle
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub origins: Vec<JsWord>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct QwikManifest {
     pub version: JsWord,
+    /// Algorithm used to compute the symbol hashes, missing from the manifests written before it
+    /// was recorded
+    #[serde(default = "legacy_hash_algorithm")]
+    pub hash_algorithm: JsWord,
     pub symbols: HashMap<JsWord, HookAnalysis>,
     pub bundles: HashMap<JsWord, QwikBundle>,
     pub mapping: HashMap<JsWord, JsWord>,
+    /// Segment bundles re-exported by every entry bundle
+    #[serde(default)]
+    pub entries: HashMap<JsWord, Vec<JsWord>>,
+}
+
+/// Symbols added, removed or moved to another entry since a previous build.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestDiff {
+    pub added: Vec<JsWord>,
+    pub removed: Vec<JsWord>,
+    pub moved: Vec<MovedSymbol>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct MovedSymbol {
+    pub symbol: JsWord,
+    pub from: Option<JsWord>,
+    pub to: Option<JsWord>,
 }
 
 impl TransformOutput {
@@ -136,38 +243,129 @@ impl TransformOutput {
             bundles: HashMap::new(),
             symbols: HashMap::new(),
             mapping: HashMap::new(),
+            entries: HashMap::new(),
             version: "1".into(),
+            hash_algorithm: HASH_ALGORITHM.into(),
         };
+        // Modules are imported with or without their extension
+        let mut paths: HashMap<&str, &TransformModule> = HashMap::new();
         for module in &self.modules {
-            if let Some(hook) = &module.hook {
-                let filename =
-                    JsWord::from(format!("{}.{}", hook.canonical_filename, hook.extension));
+            paths.insert(&module.path, module);
+            if let Some((stem, _)) = module.path.rsplit_once('.') {
+                paths.entry(stem).or_insert(module);
+            }
+        }
+        for module in &self.modules {
+            let imports = |dynamic: bool| {
+                module
+                    .imports
+                    .iter()
+                    .filter(|import| import.dynamic == dynamic)
+                    .filter_map(|import| {
+                        resolve_output_module(&paths, &module.path, &import.specifier)
+                    })
+                    .map(|imported| imported.path.as_str())
+                    .filter(|imported| *imported != module.path)
+                    .collect::<BTreeSet<_>>()
+            };
+            let static_imports = imports(false);
+            let dynamic_imports = imports(true);
+            let members: Vec<&TransformModule> = if module.hook.is_none() && module.is_entry {
+                static_imports
+                    .iter()
+                    .map(|imported| paths[imported])
+                    .filter(|imported| imported.hook.is_some())
+                    .collect()
+            } else {
+                vec![]
+            };
+            let mut bundle = QwikBundle {
+                size: module.code.len(),
+                symbols: vec![],
+                imports: static_imports.into_iter().map(JsWord::from).collect(),
+                dynamic_imports: dynamic_imports.into_iter().map(JsWord::from).collect(),
+                origins: vec![],
+            };
+            let filename = JsWord::from(module.path.as_str());
+            // Inlined segments point to the file of the module they were inlined in
+            for hook in module.hook.iter().chain(&module.inlined_hooks) {
                 manifest.mapping.insert(hook.name.clone(), filename.clone());
                 manifest.symbols.insert(hook.name.clone(), hook.clone());
-                manifest.bundles.insert(
+                bundle.symbols.push(hook.name.clone());
+                bundle.origins.push(hook.origin.clone());
+            }
+            // Entries re-export the symbols of their members
+            for member in &members {
+                if let Some(hook) = &member.hook {
+                    bundle.symbols.push(hook.name.clone());
+                    bundle.origins.push(hook.origin.clone());
+                }
+            }
+            bundle.origins.sort();
+            bundle.origins.dedup();
+            if !members.is_empty() {
+                manifest.entries.insert(
                     filename.clone(),
-                    QwikBundle {
-                        symbols: vec![hook.name.clone()],
-                        size: module.code.len(),
-                    },
+                    members
+                        .iter()
+                        .map(|member| JsWord::from(member.path.as_str()))
+                        .collect(),
                 );
             }
+            manifest.bundles.insert(filename, bundle);
         }
         manifest
     }
 
+    /// Compares the symbols of this output with the ones of the `previous` build. Symbols are
+    /// compared by entry name, not by output file, so the renames of `content_hash`, which change
+    /// with the code of the files, are not reported as moves.
+    pub fn diff_manifest(&self, previous: &QwikManifest) -> ManifestDiff {
+        let mut diff = ManifestDiff::default();
+        let mut current = HashSet::new();
+        for module in &self.modules {
+            for hook in module.hook.iter().chain(&module.inlined_hooks) {
+                current.insert(&hook.name);
+                match previous.symbols.get(&hook.name) {
+                    None => diff.added.push(hook.name.clone()),
+                    Some(previous) if previous.entry != hook.entry => {
+                        diff.moved.push(MovedSymbol {
+                            symbol: hook.name.clone(),
+                            from: previous.entry.clone(),
+                            to: hook.entry.clone(),
+                        });
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        diff.removed = previous
+            .symbols
+            .keys()
+            .filter(|symbol| !current.contains(symbol))
+            .cloned()
+            .collect();
+        diff.added.sort();
+        diff.removed.sort();
+        diff.moved.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+        diff
+    }
+
     #[cfg(feature = "fs")]
     pub fn write_to_fs(
         &self,
         destination: &Path,
         manifest: Option<String>,
+        source_maps: SourceMapsOption,
     ) -> Result<usize, Error> {
         for module in &self.modules {
-            let write_path = destination.join(&module.path);
-            fs::create_dir_all(write_path.parent().with_context(|| {
-                format!("Computing path parent of {}", write_path.to_string_lossy())
-            })?)?;
-            fs::write(write_path, &module.code)?;
+            for (path, contents) in module.output_files(source_maps) {
+                let write_path = destination.join(path);
+                fs::create_dir_all(write_path.parent().with_context(|| {
+                    format!("Computing path parent of {}", write_path.to_string_lossy())
+                })?)?;
+                fs::write(write_path, contents)?;
+            }
         }
         if let Some(manifest) = manifest {
             let write_path = destination.join(manifest);
@@ -179,7 +377,7 @@ impl TransformOutput {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct TransformModule {
     pub path: String,
@@ -188,12 +386,62 @@ pub struct TransformModule {
     pub map: Option<String>,
 
     pub hook: Option<HookAnalysis>,
+    /// Segments inlined in this module, see `inline_threshold`
+    #[serde(default)]
+    pub inlined_hooks: Vec<HookAnalysis>,
     pub is_entry: bool,
+    /// Imports of the module, collected from its AST, in order
+    #[serde(default)]
+    pub imports: Vec<ModuleImport>,
 
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub order: u64,
 }
 
+/// Import of another module, by an import declaration, a re-export or an `import()` call.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ModuleImport {
+    pub specifier: JsWord,
+    /// Imported by an `import()` call, loading the module on demand
+    pub dynamic: bool,
+}
+
+impl TransformModule {
+    /// Returns the files to write for this module as `(path, contents)` pairs.
+    ///
+    /// When the module has a source map, a `sourceMappingURL` comment is appended to the code, and
+    /// with `SourceMapsOption::External` the map itself is returned as a `.map` file next to it.
+    pub fn output_files(&self, source_maps: SourceMapsOption) -> Vec<(String, String)> {
+        let map = match &self.map {
+            Some(map) => map,
+            None => return vec![(self.path.clone(), self.code.clone())],
+        };
+        let mut code = self.code.clone();
+        if !code.ends_with('\n') {
+            code.push('\n');
+        }
+        match source_maps {
+            SourceMapsOption::External => {
+                let map_path = [&self.path, ".map"].concat();
+                let map_name = Path::new(&map_path).file_name().map_or_else(
+                    || map_path.clone(),
+                    |name| name.to_string_lossy().to_string(),
+                );
+                code.push_str(&format!("//# sourceMappingURL={}\n", map_name));
+                vec![(self.path.clone(), code), (map_path, map.clone())]
+            }
+            SourceMapsOption::Inline => {
+                code.push_str(&format!(
+                    "//# sourceMappingURL=data:application/json;charset=utf-8;base64,{}\n",
+                    base64::engine::general_purpose::STANDARD.encode(map)
+                ));
+                vec![(self.path.clone(), code)]
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct ErrorBuffer(std::sync::Arc<std::sync::Mutex<Vec<swc_common::errors::Diagnostic>>>);
 
@@ -206,20 +454,32 @@ impl Emitter for ErrorBuffer {
 pub fn transform_code(config: TransformCodeOptions) -> Result<TransformOutput, anyhow::Error> {
     let source_map = Lrc::new(SourceMap::default());
     let path_data = parse_path(config.relative_path, config.src_dir)?;
-    let module = parse(
+    let (module, recovered_errors) = parse(
         config.code,
         &path_data,
-        config.root_dir,
+        source_map_path(&path_data, config.root_dir)?,
         Lrc::clone(&source_map),
     );
+    let input_source_map = if config.source_maps {
+        parse_input_source_map(config.input_source_map, config.code)
+    } else {
+        None
+    };
     // dbg!(&module);
     let transpile_jsx = config.transpile_jsx;
     let transpile_ts = config.transpile_ts;
 
     let origin: JsWord = path_data.rel_path.to_slash_lossy().into();
+    let vendored = config.vendored || is_node_module(&path_data.rel_path);
 
     match module {
-        Ok((main_module, comments, is_type_script, is_jsx)) => {
+        Ok((main_module, comments, is_type_script, is_jsx))
+            if recovered_errors.is_empty() || config.recover_parse_errors =>
+        {
+            let suppressions = collect_suppressions(&comments, &source_map);
+            if config.source_maps {
+                strip_source_mapping_urls(&comments);
+            }
             let extension = match (transpile_ts, transpile_jsx, is_type_script, is_jsx) {
                 (true, true, _, _) => JsWord::from("js"),
                 (true, false, _, true) => JsWord::from("jsx"),
@@ -234,6 +494,9 @@ pub fn transform_code(config: TransformCodeOptions) -> Result<TransformOutput, a
                 false,
                 Box::new(error_buffer.clone()),
             );
+            for err in recovered_errors {
+                err.into_diagnostic(&handler).emit();
+            }
 
             swc_common::GLOBALS.set(&Globals::new(), || {
                 swc_common::errors::HANDLER.set(&handler, || {
@@ -322,6 +585,9 @@ pub fn transform_code(config: TransformCodeOptions) -> Result<TransformOutput, a
                         mode: config.mode,
                         core_module: config.core_module,
                         entry_strategy: config.entry_strategy,
+                        inline_threshold: config.inline_threshold,
+                        qrl_base: config.qrl_base,
+                        segment_dir: config.segment_dir,
                         reg_ctx_name: config.reg_ctx_name,
                         strip_ctx_name: config.strip_ctx_name,
                         strip_event_handlers: config.strip_event_handlers,
@@ -374,16 +640,48 @@ pub fn transform_code(config: TransformCodeOptions) -> Result<TransformOutput, a
                             ));
                         }
                     }
+                    main_module = downlevel(main_module, config.target, &comments, unresolved_mark);
+                    if config.minify == MinifyMode::Minify {
+                        main_module = minify(
+                            main_module,
+                            &source_map,
+                            &comments,
+                            unresolved_mark,
+                            top_level_mark,
+                        );
+                    }
                     main_module.visit_mut_with(&mut hygiene_with_config(Default::default()));
                     main_module.visit_mut_with(&mut fixer(None));
 
+                    let emit_options = EmitOptions {
+                        root_dir: config.root_dir,
+                        source_maps: config.source_maps,
+                        sources_content: config.sources_content,
+                        input_source_map: input_source_map.as_ref(),
+                        minify: config.minify == MinifyMode::Minify,
+                        target: config.target,
+                    };
+                    let a = if did_transform && !config.preserve_filenames {
+                        [&path_data.file_stem, ".", &extension].concat()
+                    } else {
+                        path_data.file_name.clone()
+                    };
+                    let path = path_data.rel_dir.join(a).to_string_lossy().to_string();
+
                     let hooks = qwik_transform.hooks;
+                    let mut inlined_hooks =
+                        group_inlined_hooks(qwik_transform.inlined_hooks, &hooks, &path);
                     let mut modules: Vec<TransformModule> = Vec::with_capacity(hooks.len() + 10);
 
+                    let (segment_dir, _) = split_segment_dir(config.segment_dir);
+                    let segment_base_dir = path_data.base_dir.join(segment_dir);
                     let comments_maps = comments.clone().take_all();
                     for h in hooks.into_iter() {
                         let is_entry = h.entry.is_none();
-                        let hook_path = [&h.canonical_filename, ".", &h.data.extension].concat();
+                        let hook_path = join_segment_dir(
+                            segment_dir,
+                            &[&h.canonical_filename, ".", &h.data.extension].concat(),
+                        );
                         let need_handle_watch =
                             might_need_handle_watch(&h.data.ctx_kind, &h.data.ctx_name) && is_entry;
 
@@ -395,6 +693,7 @@ pub fn transform_code(config: TransformCodeOptions) -> Result<TransformOutput, a
                             scoped_idents: &h.data.scoped_idents,
                             need_transform: h.data.need_transform,
                             explicit_extensions: qwik_transform.options.explicit_extensions,
+                            segment_dir: &segment_base_dir,
                             global: &qwik_transform.options.global_collect,
                             core_module: &qwik_transform.options.core_module,
                             need_handle_watch,
@@ -414,6 +713,17 @@ pub fn transform_code(config: TransformCodeOptions) -> Result<TransformOutput, a
                                 },
                             ));
                         }
+                        hook_module =
+                            downlevel(hook_module, config.target, &comments, unresolved_mark);
+                        if config.minify == MinifyMode::Minify {
+                            hook_module = minify(
+                                hook_module,
+                                &source_map,
+                                &comments,
+                                unresolved_mark,
+                                top_level_mark,
+                            );
+                        }
                         hook_module.visit_mut_with(&mut hygiene_with_config(Default::default()));
                         hook_module.visit_mut_with(&mut fixer(None));
 
@@ -421,17 +731,19 @@ pub fn transform_code(config: TransformCodeOptions) -> Result<TransformOutput, a
                             Lrc::clone(&source_map),
                             Some(comments),
                             &hook_module,
-                            config.root_dir,
-                            config.source_maps,
-                        )
-                        .unwrap();
+                            &emit_options,
+                        )?;
 
                         modules.push(TransformModule {
                             code,
                             map,
                             is_entry,
+                            imports: collect_imports(&hook_module),
                             path: hook_path,
                             order: h.hash,
+                            inlined_hooks: inlined_hooks
+                                .remove(&Some(h.name.clone()))
+                                .unwrap_or_default(),
                             hook: Some(HookAnalysis {
                                 origin: h.data.origin,
                                 name: h.name,
@@ -453,18 +765,10 @@ pub fn transform_code(config: TransformCodeOptions) -> Result<TransformOutput, a
                         Lrc::clone(&source_map),
                         Some(comments),
                         &main_module,
-                        config.root_dir,
-                        config.source_maps,
+                        &emit_options,
                     )?;
 
-                    let a = if did_transform && !config.preserve_filenames {
-                        [&path_data.file_stem, ".", &extension].concat()
-                    } else {
-                        path_data.file_name
-                    };
-                    let path = path_data.rel_dir.join(a).to_string_lossy().to_string();
-
-                    let mut hasher = DefaultHasher::new();
+                    let mut hasher = stable_hasher();
                     hasher.write(path.as_bytes());
 
                     modules.push(TransformModule {
@@ -472,46 +776,72 @@ pub fn transform_code(config: TransformCodeOptions) -> Result<TransformOutput, a
                         path,
                         code,
                         map,
+                        imports: collect_imports(&main_module),
                         order: hasher.finish(),
                         hook: None,
+                        inlined_hooks: inlined_hooks.remove(&None).unwrap_or_default(),
                     });
 
-                    let diagnostics = handle_error(&error_buffer, origin, &source_map);
+                    let diagnostics = apply_suppressions(
+                        handle_error(&error_buffer, origin.clone(), &source_map, vendored),
+                        suppressions,
+                        &origin,
+                    );
                     Ok(TransformOutput {
                         modules,
                         diagnostics,
                         is_type_script,
                         is_jsx,
+                        manifest_diff: None,
                     })
                 })
             })
         }
-        Err(err) => {
+        // Reports every syntax error, the recoverable ones before the one that stopped the parser
+        module => {
             let error_buffer = ErrorBuffer::default();
             let handler = Handler::with_emitter(true, false, Box::new(error_buffer.clone()));
-            err.into_diagnostic(&handler).emit();
-            let diagnostics = handle_error(&error_buffer, origin, &source_map);
+            for err in recovered_errors.into_iter().chain(module.err()) {
+                err.into_diagnostic(&handler).emit();
+            }
+            let diagnostics = handle_error(&error_buffer, origin, &source_map, vendored);
             Ok(TransformOutput {
                 modules: vec![],
                 diagnostics,
                 is_type_script: false,
                 is_jsx: false,
+                manifest_diff: None,
             })
         }
     }
 }
 
+/// Path naming the module in the source maps, relative to `root_dir` when it is set.
+fn source_map_path(
+    path_data: &PathData,
+    root_dir: Option<&Path>,
+) -> Result<PathBuf, TransformError> {
+    match root_dir {
+        Some(root_dir) => pathdiff::diff_paths(&path_data.abs_path, root_dir).ok_or_else(|| {
+            TransformError::InvalidPath(format!(
+                "{} can not be made relative to the root directory {}",
+                path_data.abs_path.display(),
+                root_dir.display()
+            ))
+        }),
+        None => Ok(path_data.abs_path.clone()),
+    }
+}
+
+type ParsedModule = (ast::Module, SingleThreadedComments, bool, bool);
+
+/// Parses the module, returning the syntax errors the parser recovered from along with the result.
 fn parse(
     code: &str,
     path_data: &PathData,
-    root_dir: Option<&Path>,
+    sm_path: PathBuf,
     source_map: Lrc<SourceMap>,
-) -> PResult<(ast::Module, SingleThreadedComments, bool, bool)> {
-    let sm_path = if let Some(root_dir) = root_dir {
-        pathdiff::diff_paths(path_data.abs_path.clone(), root_dir).unwrap()
-    } else {
-        path_data.abs_path.clone()
-    };
+) -> (PResult<ParsedModule>, Vec<ParserError>) {
     let source_file = source_map.new_source_file(FileName::Real(sm_path), code.into());
 
     let comments = SingleThreadedComments::default();
@@ -538,10 +868,12 @@ fn parse(
     );
 
     let mut parser = Parser::new_from(lexer);
-    match parser.parse_module() {
-        Err(err) => Err(err),
-        Ok(module) => Ok((module, comments, is_type_script, is_jsx)),
-    }
+    let module = parser.parse_module();
+    let errors = parser.take_errors();
+    (
+        module.map(|module| (module, comments, is_type_script, is_jsx)),
+        errors,
+    )
 }
 
 fn parse_filename(path_data: &PathData) -> (bool, bool) {
@@ -559,13 +891,73 @@ fn parse_filename(path_data: &PathData) -> (bool, bool) {
     }
 }
 
+/// Groups the inlined segments by the segment whose module they end up in, `None` being the main
+/// module at `main_path`, and points their filename to that module.
+fn group_inlined_hooks(
+    inlined_hooks: Vec<InlinedHook>,
+    hooks: &[Hook],
+    main_path: &str,
+) -> HashMap<Option<JsWord>, Vec<HookAnalysis>> {
+    let parents: HashMap<JsWord, Option<JsWord>> = inlined_hooks
+        .iter()
+        .map(|h| (h.name.clone(), h.data.parent_hook.clone()))
+        .collect();
+    let mut groups: HashMap<Option<JsWord>, Vec<HookAnalysis>> = HashMap::new();
+    for h in inlined_hooks {
+        // Segments inlined in an inlined segment end up in the module of the latter
+        let mut container = h.data.parent_hook.clone();
+        while let Some(parent) = container.as_ref().and_then(|name| parents.get(name)) {
+            container = parent.clone();
+        }
+        let container = container.and_then(|name| hooks.iter().find(|hook| hook.name == name));
+        let (canonical_filename, extension) = match container {
+            Some(hook) => (hook.canonical_filename.clone(), hook.data.extension.clone()),
+            None => {
+                let (stem, extension) = main_path.rsplit_once('.').unwrap_or((main_path, ""));
+                (JsWord::from(stem), JsWord::from(extension))
+            }
+        };
+        groups
+            .entry(container.map(|hook| hook.name.clone()))
+            .or_default()
+            .push(HookAnalysis {
+                origin: h.data.origin,
+                name: h.name,
+                entry: None,
+                extension,
+                canonical_filename,
+                parent: h.data.parent_hook,
+                ctx_kind: h.data.ctx_kind,
+                ctx_name: h.data.ctx_name,
+                captures: !h.data.scoped_idents.is_empty(),
+                display_name: h.data.display_name,
+                hash: h.data.hash,
+                loc: (h.span.lo.0, h.span.hi.0),
+            });
+    }
+    groups
+}
+
+#[derive(Default)]
+pub struct EmitOptions<'a> {
+    pub root_dir: Option<&'a Path>,
+    pub source_maps: bool,
+    /// Embeds the original sources in the source maps
+    pub sources_content: bool,
+    /// Source map of the input, the emitted map is chained with it
+    pub input_source_map: Option<&'a sourcemap::SourceMap>,
+    /// Emits minified code, without comments
+    pub minify: bool,
+    pub target: EsTarget,
+}
+
 pub fn emit_source_code(
     source_map: Lrc<SourceMap>,
     comments: Option<SingleThreadedComments>,
     program: &ast::Module,
-    root_dir: Option<&Path>,
-    source_maps: bool,
-) -> Result<(String, Option<String>), Error> {
+    options: &EmitOptions,
+) -> Result<(String, Option<String>), TransformError> {
+    let comments = if options.minify { None } else { comments };
     let mut src_map_buf = Vec::new();
     let mut buf = Vec::new();
     {
@@ -573,15 +965,15 @@ pub fn emit_source_code(
             Lrc::clone(&source_map),
             "\n",
             &mut buf,
-            if source_maps {
+            if options.source_maps {
                 Some(&mut src_map_buf)
             } else {
                 None
             },
         ));
         let config = swc_ecmascript::codegen::Config {
-            minify: false,
-            target: ast::EsVersion::latest(),
+            minify: options.minify,
+            target: options.target.es_version(),
             ascii_only: false,
             omit_last_semi: false,
         };
@@ -591,14 +983,22 @@ pub fn emit_source_code(
             cm: Lrc::clone(&source_map),
             wr: writer,
         };
-        emitter.emit_module(program)?;
+        emitter
+            .emit_module(program)
+            .map_err(|err| TransformError::Emit(err.to_string()))?;
     }
 
     let mut map_buf = vec![];
-    let emit_source_maps = if source_maps {
-        let mut s = source_map.build_source_map(&src_map_buf);
-        if let Some(root_dir) = root_dir {
-            s.set_source_root(Some(root_dir.to_str().unwrap()));
+    let emit_source_maps = if options.source_maps {
+        let mut s = source_map.build_source_map_with_config(
+            &src_map_buf,
+            options.input_source_map,
+            SourceMapConfig {
+                sources_content: options.sources_content,
+            },
+        );
+        if let Some(root_dir) = options.root_dir {
+            s.set_source_root(Some(root_dir.to_string_lossy().as_ref()));
         }
         s.to_writer(&mut map_buf).is_ok()
     } else {
@@ -614,10 +1014,152 @@ pub fn emit_source_code(
     }
 }
 
+/// Lowers the syntax of the editions newer than `target`, from the newest to the oldest. The
+/// helpers the passes rely on, like `_define_property` for class fields, are inlined in `module`.
+fn downlevel(
+    module: ast::Module,
+    target: EsTarget,
+    comments: &SingleThreadedComments,
+    unresolved_mark: Mark,
+) -> ast::Module {
+    HELPERS.set(&Helpers::new(false), || {
+        let mut module = module;
+        if target < EsTarget::Es2022 {
+            module = module.fold_with(&mut compat::es2022::es2022(
+                Some(comments),
+                Default::default(),
+            ));
+        }
+        if target < EsTarget::Es2021 {
+            module = module.fold_with(&mut compat::es2021::es2021());
+        }
+        if target < EsTarget::Es2020 {
+            module = module.fold_with(&mut compat::es2020::es2020(Default::default()));
+        }
+        if target < EsTarget::Es2019 {
+            module = module.fold_with(&mut compat::es2019::es2019());
+        }
+        if target < EsTarget::Es2018 {
+            module = module.fold_with(&mut compat::es2018::es2018(Default::default()));
+        }
+        module.visit_mut_with(&mut inject_helpers(unresolved_mark));
+        module
+    })
+}
+
+/// Compresses and mangles `module`, exports are never renamed nor dropped since mangling of top
+/// level bindings is disabled. The hygiene pass still has to run on the result, the compressor
+/// can leave identifiers that only differ by their syntax context.
+fn minify(
+    module: ast::Module,
+    source_map: &Lrc<SourceMap>,
+    comments: &SingleThreadedComments,
+    unresolved_mark: Mark,
+    top_level_mark: Mark,
+) -> ast::Module {
+    let options = MinifyOptions {
+        compress: Some(CompressOptions::default()),
+        mangle: Some(MangleOptions::default()),
+        ..Default::default()
+    };
+    minifier::optimize(
+        ast::Program::Module(module),
+        Lrc::clone(source_map),
+        Some(comments),
+        None,
+        &options,
+        &ExtraOptions {
+            unresolved_mark,
+            top_level_mark,
+        },
+    )
+    .expect_module()
+}
+
+/// Returns the URL of the `sourceMappingURL` comment at the end of `code`, if any.
+pub fn find_source_mapping_url(code: &str) -> Option<&str> {
+    code.lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .and_then(|line| {
+            line.strip_prefix("//# sourceMappingURL=")
+                .or_else(|| line.strip_prefix("//@ sourceMappingURL="))
+        })
+        .map(str::trim)
+}
+
+/// Parses the source map of the input, either the one passed by the caller or the one inlined as a
+/// data URL in the code, so the emitted maps point to the original sources instead of the input.
+fn parse_input_source_map(
+    input_source_map: Option<&str>,
+    code: &str,
+) -> Option<sourcemap::SourceMap> {
+    match input_source_map {
+        Some(map) => sourcemap::SourceMap::from_slice(map.as_bytes()).ok(),
+        None => {
+            let url = find_source_mapping_url(code)?;
+            let (media_type, data) = url.strip_prefix("data:")?.split_once(',')?;
+            if !media_type.starts_with("application/json") || !media_type.ends_with(";base64") {
+                return None;
+            }
+            let map = base64::engine::general_purpose::STANDARD
+                .decode(data)
+                .ok()?;
+            sourcemap::SourceMap::from_slice(&map).ok()
+        }
+    }
+}
+
+/// Removes the `sourceMappingURL` comments of the input, they would point the output to the map
+/// of the input.
+fn strip_source_mapping_urls(comments: &SingleThreadedComments) {
+    let (mut leading, mut trailing) = comments.borrow_all_mut();
+    for comments in leading.values_mut().chain(trailing.values_mut()) {
+        comments.retain(|comment| {
+            !comment.text.starts_with("# sourceMappingURL=")
+                && !comment.text.starts_with("@ sourceMappingURL=")
+        });
+    }
+}
+
+struct SourceMapConfig {
+    sources_content: bool,
+}
+
+impl SourceMapGenConfig for SourceMapConfig {
+    fn file_name_to_source(&self, f: &FileName) -> String {
+        f.to_string()
+    }
+
+    fn inline_sources_content(&self, _f: &FileName) -> bool {
+        self.sources_content
+    }
+}
+
+/// Whether the module is a dependency installed in `node_modules`.
+fn is_node_module(path: &Path) -> bool {
+    path.components()
+        .any(|component| component.as_os_str() == "node_modules")
+}
+
+/// Category of the diagnostics of the given level, the errors of third-party code are reported as
+/// `SourceError`.
+const fn diagnostic_category(level: Level, vendored: bool) -> DiagnosticCategory {
+    match level {
+        Level::Bug | Level::Fatal | Level::PhaseFatal | Level::Error if vendored => {
+            DiagnosticCategory::SourceError
+        }
+        Level::Bug | Level::Fatal | Level::PhaseFatal | Level::Error => DiagnosticCategory::Error,
+        _ => DiagnosticCategory::Warning,
+    }
+}
+
 fn handle_error(
     error_buffer: &ErrorBuffer,
     origin: JsWord,
     source_map: &Lrc<SourceMap>,
+    vendored: bool,
 ) -> Vec<Diagnostic> {
     error_buffer
         .0
@@ -674,13 +1216,14 @@ fn handle_error(
                 message,
                 highlights,
                 suggestions,
-                category: DiagnosticCategory::Error,
+                category: diagnostic_category(diagnostic.level, vendored),
                 scope: DiagnosticScope::Optimizer,
             }
         })
         .collect()
 }
 
+/// Paths of the module being transformed.
 pub struct PathData {
     pub abs_path: PathBuf,
     pub rel_path: PathBuf,
@@ -693,27 +1236,65 @@ pub struct PathData {
     pub file_prefix: String,
 }
 
+/// Resolves the `specifier` imported by the output module at `importer` to another output module.
+fn resolve_output_module<'a>(
+    paths: &HashMap<&str, &'a TransformModule>,
+    importer: &str,
+    specifier: &str,
+) -> Option<&'a TransformModule> {
+    if specifier.starts_with('.') {
+        let dir = importer.rsplit_once('/').map_or("", |(dir, _)| dir);
+        let path = relative_path::RelativePath::new(dir)
+            .join(specifier)
+            .normalize();
+        paths.get(path.as_str()).copied()
+    } else if specifier.starts_with('/') || specifier.contains("://") {
+        // QRLs importing from `qrl_base`
+        paths
+            .iter()
+            .find(|(path, _)| {
+                specifier.len() > path.len()
+                    && specifier.ends_with(*path)
+                    && specifier[..specifier.len() - path.len()].ends_with('/')
+            })
+            .map(|(_, module)| *module)
+    } else {
+        None
+    }
+}
+
 pub fn parse_path(src: &str, base_dir: &Path) -> Result<PathData, Error> {
     let path = Path::new(src);
+    let invalid_path = |reason: &str| TransformError::InvalidPath(format!("{} {}", src, reason));
     let file_stem = path
         .file_stem()
         .and_then(OsStr::to_str)
         .map(Into::into)
-        .with_context(|| format!("Computing file stem for {}", path.to_string_lossy()))?;
-
-    let rel_dir = path.parent().unwrap().to_path_buf();
-    let extension = path.extension().and_then(OsStr::to_str).unwrap();
+        .ok_or_else(|| invalid_path("has no file name"))?;
+
+    let rel_dir = path
+        .parent()
+        .ok_or_else(|| invalid_path("has no parent directory"))?
+        .to_path_buf();
+    let extension = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .ok_or_else(|| invalid_path("has no extension"))?;
     let file_name = path
         .file_name()
         .and_then(OsStr::to_str)
-        .with_context(|| format!("Computing filename for {}", path.to_string_lossy()))?;
+        .ok_or_else(|| invalid_path("has no file name"))?;
     let file_prefix = file_name
         .rsplitn(2, '.')
         .last()
-        .with_context(|| format!("Computing file_prefix for {}", path.to_string_lossy()))?;
+        .ok_or_else(|| invalid_path("has no file name"))?;
 
     let abs_path = normalize_path(base_dir.join(path));
-    let abs_dir = normalize_path(abs_path.parent().unwrap());
+    let abs_dir = normalize_path(
+        abs_path
+            .parent()
+            .ok_or_else(|| invalid_path("has no parent directory"))?,
+    );
 
     Ok(PathData {
         abs_path,
@@ -0,0 +1,111 @@
+//! Comments silencing diagnostics of the optimizer.
+//!
+//! `// qwik-optimizer-disable-next-line C02` silences the diagnostics of the given codes reported
+//! on the next line, and `/* qwik-optimizer-disable C03 */` the ones reported anywhere in the file.
+//! Several codes can be listed, separated by spaces or commas. A suppression that silences nothing
+//! is reported as a warning, so they do not outlive the code they were written for.
+
+use crate::errors;
+use crate::utils::{Diagnostic, DiagnosticCategory, DiagnosticScope, SourceLocation};
+
+use swc_atoms::JsWord;
+use swc_common::comments::SingleThreadedComments;
+use swc_common::SourceMap;
+
+const DISABLE_NEXT_LINE: &str = "qwik-optimizer-disable-next-line";
+const DISABLE: &str = "qwik-optimizer-disable";
+
+pub struct Suppression {
+    code: String,
+    /// Line whose diagnostics are silenced, `None` for the whole file
+    line: Option<usize>,
+    loc: SourceLocation,
+    used: bool,
+}
+
+/// Reads the suppressions of the comments of a module, they must be collected before the module
+/// is transformed, as transforms drop comments.
+pub fn collect_suppressions(
+    comments: &SingleThreadedComments,
+    source_map: &SourceMap,
+) -> Vec<Suppression> {
+    let (leading, trailing) = comments.borrow_all();
+    let mut suppressions = vec![];
+    for comment in leading.values().chain(trailing.values()).flatten() {
+        let text = comment.text.trim();
+        let (codes, line) = if let Some(codes) = text.strip_prefix(DISABLE_NEXT_LINE) {
+            let line = source_map.lookup_char_pos(comment.span.hi).line;
+            (codes, Some(line + 1))
+        } else if let Some(codes) = text.strip_prefix(DISABLE) {
+            (codes, None)
+        } else {
+            continue;
+        };
+        if !codes.starts_with(char::is_whitespace) {
+            continue;
+        }
+        let loc = SourceLocation::from(source_map, comment.span);
+        for code in codes
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|code| !code.is_empty())
+        {
+            suppressions.push(Suppression {
+                code: code.to_string(),
+                line,
+                loc: loc.clone(),
+                used: false,
+            });
+        }
+    }
+    suppressions.sort_by_key(|suppression| suppression.loc.lo);
+    suppressions
+}
+
+/// Removes the diagnostics silenced by `suppressions`, and reports the suppressions that silenced
+/// none.
+pub fn apply_suppressions(
+    diagnostics: Vec<Diagnostic>,
+    mut suppressions: Vec<Suppression>,
+    origin: &JsWord,
+) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = diagnostics
+        .into_iter()
+        .filter(|diagnostic| {
+            let line = diagnostic
+                .highlights
+                .as_ref()
+                .and_then(|highlights| highlights.first())
+                .map(|highlight| highlight.start_line);
+            let mut suppressed = false;
+            for suppression in &mut suppressions {
+                if diagnostic.code.as_ref() == Some(&suppression.code)
+                    && (suppression.line.is_none() || suppression.line == line)
+                {
+                    suppression.used = true;
+                    suppressed = true;
+                }
+            }
+            !suppressed
+        })
+        .collect();
+    diagnostics.extend(
+        suppressions
+            .into_iter()
+            .filter(|suppression| !suppression.used)
+            .map(|suppression| Diagnostic {
+                category: DiagnosticCategory::Warning,
+                code: Some(errors::get_diagnostic_code(
+                    errors::Error::UnusedSuppression,
+                )),
+                file: origin.clone(),
+                message: format!(
+                    "Suppression of {} does not match any diagnostic",
+                    suppression.code
+                ),
+                highlights: Some(vec![suppression.loc]),
+                suggestions: None,
+                scope: DiagnosticScope::Optimizer,
+            }),
+    );
+    diagnostics
+}
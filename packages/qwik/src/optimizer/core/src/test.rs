@@ -4,7 +4,7 @@ use super::*;
 use serde_json::to_string_pretty;
 
 macro_rules! test_input {
-    ($input: expr) => {
+    ($input: expr) => {{
         let input = $input;
         let strip_exports: Option<Vec<JsWord>> = input
             .strip_exports
@@ -24,9 +24,13 @@ macro_rules! test_input {
             input: vec![TransformModuleInput {
                 code: input.code.clone(),
                 path: input.filename,
+                input_source_map: input.input_source_map,
+                vendored: false,
             }],
             source_maps: true,
+            sources_content: input.sources_content,
             minify: input.minify,
+            target: input.target,
             transpile_ts: input.transpile_ts,
             transpile_jsx: input.transpile_jsx,
             preserve_filenames: input.preserve_filenames,
@@ -41,14 +45,22 @@ macro_rules! test_input {
             reg_ctx_name,
             strip_event_handlers: input.strip_event_handlers,
             is_server: input.is_server,
+            usage_profile: input.usage_profile,
+            size_limits: input.size_limits,
+            inline_threshold: input.inline_threshold,
+            content_hash: input.content_hash,
+            qrl_base: input.qrl_base,
+            segment_dir: input.segment_dir,
+            recover_parse_errors: input.recover_parse_errors,
+            ..test_options()
         });
         if input.snapshot {
             let input = input.code.to_string();
             let output = format!("==INPUT==\n\n{}", input);
             snapshot_res!(&res, output);
         }
-        drop(res)
-    };
+        res
+    }};
 }
 
 macro_rules! snapshot_res {
@@ -3254,28 +3266,19 @@ export const Local = component$(() => {
             TransformModuleInput {
                 code: dep.into(),
                 path: "../../node_modules/dep/dist/lib.mjs".into(),
+                input_source_map: None,
+                vendored: false,
             },
             TransformModuleInput {
                 code: code.into(),
                 path: "components/main.tsx".into(),
+                input_source_map: None,
+                vendored: false,
             },
         ],
         source_maps: true,
-        minify: MinifyMode::Simplify,
         explicit_extensions: true,
-        mode: EmitMode::Lib,
-        manual_chunks: None,
-        entry_strategy: EntryStrategy::Hook,
-        transpile_ts: true,
-        transpile_jsx: true,
-        preserve_filenames: false,
-        core_module: None,
-        scope: None,
-        strip_exports: None,
-        strip_ctx_name: None,
-        strip_event_handlers: false,
-        reg_ctx_name: None,
-        is_server: None,
+        ..test_options()
     });
     snapshot_res!(&res, "".into());
 }
@@ -3333,29 +3336,19 @@ export const Greeter = component$(() => {
             TransformModuleInput {
                 code: code.into(),
                 path: "main.tsx".into(),
+                input_source_map: None,
+                vendored: false,
             },
             TransformModuleInput {
                 code: code.into(),
                 path: "components/main.tsx".into(),
+                input_source_map: None,
+                vendored: false,
             },
         ],
         source_maps: true,
-        minify: MinifyMode::Simplify,
-        root_dir: None,
         explicit_extensions: true,
-        mode: EmitMode::Lib,
-        manual_chunks: None,
-        entry_strategy: EntryStrategy::Hook,
-        transpile_ts: true,
-        transpile_jsx: true,
-        preserve_filenames: false,
-        scope: None,
-        core_module: None,
-        reg_ctx_name: None,
-        strip_exports: None,
-        strip_ctx_name: None,
-        strip_event_handlers: false,
-        is_server: None,
+        ..test_options()
     });
     let ref_hooks: Vec<_> = res
         .unwrap()
@@ -3371,29 +3364,22 @@ export const Greeter = component$(() => {
                 TransformModuleInput {
                     code: code.into(),
                     path: "main.tsx".into(),
+                    input_source_map: None,
+                    vendored: false,
                 },
                 TransformModuleInput {
                     code: code.into(),
                     path: "components/main.tsx".into(),
+                    input_source_map: None,
+                    vendored: false,
                 },
             ],
-            root_dir: None,
-            source_maps: false,
-            minify: MinifyMode::Simplify,
             explicit_extensions: true,
             mode: option.0,
-            manual_chunks: None,
             entry_strategy: option.1,
             transpile_ts: option.2,
             transpile_jsx: option.2,
-            preserve_filenames: false,
-            scope: None,
-            core_module: None,
-            strip_exports: None,
-            strip_ctx_name: None,
-            strip_event_handlers: false,
-            reg_ctx_name: None,
-            is_server: None,
+            ..test_options()
         });
 
         let hooks: Vec<_> = res
@@ -3476,78 +3462,1296 @@ fn example_of_synchronous_qrl() {
     });
 }
 
-// TODO(misko): Make this test work by implementing strict serialization.
-// #[test]
-// fn example_of_synchronous_qrl_that_cant_be_serialized() {
-//     test_input!(TestInput {
-//         code: r#"
-//         import { sync$, component$ } from "@builder.io/qwik";
+#[test]
+fn transform_cache_reuses_unchanged_inputs() {
+    let code = r#"
+import { component$, $ } from '@builder.io/qwik';
 
-//         export default component$(() => {
-//         return (
-//             <input onClick$={sync$(function(event, target) {
-//                 console.log(component$);
-//             })}/>
-//         );
-//         });
-//         "#
-//         .to_string(),
-//         transpile_ts: true,
-//         transpile_jsx: true,
-//         ..TestInput::default()
-//     });
-// }
+export const Greeter = component$(() => {
+    return (
+        <div onClick$={() => console.log('greet')}/>
+    )
+});
+"#;
+    let options = |other: &str| TransformModulesOptions {
+        input: vec![
+            TransformModuleInput {
+                code: code.into(),
+                path: "greeter.tsx".into(),
+                input_source_map: None,
+                vendored: false,
+            },
+            TransformModuleInput {
+                code: other.into(),
+                path: "other.tsx".into(),
+                input_source_map: None,
+                vendored: false,
+            },
+        ],
+        entry_strategy: EntryStrategy::Smart,
+        ..test_options()
+    };
 
-fn get_hash(name: &str) -> String {
-    name.split('_').last().unwrap().into()
+    let cache = TransformCache::new();
+    let uncached = transform_modules(options(code)).unwrap();
+    let first = transform_modules_with_cache(options(code), &cache).unwrap();
+    assert_eq!(cache.misses(), 2);
+    assert_eq!(cache.hits(), 0);
+    assert_eq!(cache.len(), 2);
+
+    let second = transform_modules_with_cache(options(code), &cache).unwrap();
+    assert_eq!(cache.misses(), 2);
+    assert_eq!(cache.hits(), 2);
+
+    let paths = |output: &TransformOutput| -> Vec<(String, String)> {
+        output
+            .modules
+            .iter()
+            .map(|m| (m.path.clone(), m.code.clone()))
+            .collect()
+    };
+    assert_eq!(paths(&uncached), paths(&first));
+    assert_eq!(paths(&first), paths(&second));
+
+    let changed = code.replace("greet", "hello");
+    transform_modules_with_cache(options(&changed), &cache).unwrap();
+    assert_eq!(cache.misses(), 3);
+    assert_eq!(cache.hits(), 3);
+    assert_eq!(cache.len(), 2);
 }
 
-struct TestInput {
-    pub code: String,
-    pub filename: String,
-    pub src_dir: String,
-    pub root_dir: Option<String>,
-    pub manual_chunks: Option<HashMap<String, JsWord>>,
-    pub entry_strategy: EntryStrategy,
-    pub minify: MinifyMode,
-    pub transpile_ts: bool,
-    pub transpile_jsx: bool,
-    pub preserve_filenames: bool,
-    pub explicit_extensions: bool,
-    pub snapshot: bool,
-    pub mode: EmitMode,
-    pub core_module: Option<String>,
-    pub scope: Option<String>,
-    pub strip_exports: Option<Vec<String>>,
-    pub reg_ctx_name: Option<Vec<String>>,
-    pub strip_ctx_name: Option<Vec<String>>,
-    pub strip_event_handlers: bool,
-    pub is_server: Option<bool>,
+#[test]
+fn source_maps_output_files() {
+    let res = test_input!(TestInput {
+        code: r#"
+import { component$ } from '@builder.io/qwik';
+
+export const Greeter = component$(() => {
+    return <div onClick$={() => console.log('greet')}/>
+});
+"#
+        .to_string(),
+        filename: "greeter.tsx".to_string(),
+        transpile_ts: true,
+        transpile_jsx: true,
+        sources_content: true,
+        ..TestInput::default()
+    })
+    .unwrap();
+
+    for module in &res.modules {
+        let map = module.map.as_ref().unwrap();
+        let files = module.output_files(SourceMapsOption::External);
+        let map_path = format!("{}.map", module.path);
+        let map_name = Path::new(&map_path).file_name().unwrap().to_str().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].0, module.path);
+        assert!(files[0]
+            .1
+            .ends_with(&format!("\n//# sourceMappingURL={}\n", map_name)));
+        assert_eq!(files[1], (map_path, map.clone()));
+
+        let files = module.output_files(SourceMapsOption::Inline);
+        assert_eq!(files.len(), 1);
+        assert!(files[0]
+            .1
+            .contains("//# sourceMappingURL=data:application/json;charset=utf-8;base64,"));
+    }
+
+    let module = TransformModule {
+        path: "main.js".into(),
+        code: "export {};".into(),
+        ..TransformModule::default()
+    };
+    assert_eq!(
+        module.output_files(SourceMapsOption::External),
+        vec![("main.js".to_string(), "export {};".to_string())]
+    );
 }
 
-impl TestInput {
-    pub fn default() -> Self {
-        Self {
-            filename: "test.tsx".to_string(),
-            src_dir: "/user/qwik/src/".to_string(),
-            root_dir: None,
-            code: "/user/qwik/src/".to_string(),
-            manual_chunks: None,
-            entry_strategy: EntryStrategy::Hook,
-            minify: MinifyMode::Simplify,
-            transpile_ts: false,
-            transpile_jsx: false,
-            preserve_filenames: false,
-            explicit_extensions: false,
-            snapshot: true,
-            mode: EmitMode::Lib,
-            scope: None,
-            core_module: None,
-            reg_ctx_name: None,
-            strip_exports: None,
-            strip_ctx_name: None,
-            strip_event_handlers: false,
-            is_server: None,
+#[test]
+fn source_maps_for_entry_modules() {
+    let res = test_input!(TestInput {
+        code: r#"
+import { component$, $ } from '@builder.io/qwik';
+
+export const Greeter = component$(() => {
+    return <div onClick$={() => console.log('greet')}/>
+});
+"#
+        .to_string(),
+        filename: "greeter.tsx".to_string(),
+        entry_strategy: EntryStrategy::Smart,
+        transpile_ts: true,
+        transpile_jsx: true,
+        ..TestInput::default()
+    })
+    .unwrap();
+
+    let entries: Vec<_> = res
+        .modules
+        .iter()
+        .filter(|m| m.is_entry && m.hook.is_none())
+        .collect();
+    assert!(!entries.is_empty());
+    for entry in entries {
+        let map = entry.map.as_ref().unwrap();
+        let source_map = sourcemap::SourceMap::from_slice(map.as_bytes()).unwrap();
+        let segments = res.modules.iter().filter(|m| {
+            m.hook.as_ref().map_or(false, |hook| {
+                hook.entry.as_deref() == entry.path.strip_suffix(".js")
+            })
+        });
+        for (line, segment) in segments.enumerate() {
+            // Each re-export maps to the declaration of its symbol in the segment
+            let name = &segment.hook.as_ref().unwrap().name;
+            let export_line = segment
+                .code
+                .lines()
+                .position(|l| l.starts_with(&format!("export const {}", name)))
+                .unwrap();
+            let token = source_map.lookup_token(line as u32, 0).unwrap();
+            assert_eq!(token.get_source(), Some(&*segment.path));
+            assert_eq!(token.get_src(), (export_line as u32, 13));
         }
     }
 }
+
+#[test]
+fn input_source_maps() {
+    use base64::Engine;
+
+    let map = r#"{"version":3,"sources":["original.mdx"],"names":[],"mappings":"AAAA;AACA"}"#;
+    let code = "export const a = 1;\nexport const b = 2;\n";
+    let inline = format!(
+        "{}//# sourceMappingURL=data:application/json;charset=utf-8;base64,{}\n",
+        code,
+        base64::engine::general_purpose::STANDARD.encode(map)
+    );
+    // Without input source map, with one given along the code, and with one inlined in the code
+    for (code, input_source_map) in [
+        (code.to_string(), None),
+        (code.to_string(), Some(map.to_string())),
+        (inline, None),
+    ] {
+        test_input!(TestInput {
+            code,
+            filename: "doc.js".to_string(),
+            input_source_map,
+            ..TestInput::default()
+        });
+    }
+}
+
+#[test]
+fn minify_keeps_exported_symbols() {
+    test_input!(TestInput {
+        code: r#"
+import { component$ } from '@builder.io/qwik';
+
+const greeting = { text: 'Hello Qwik' };
+
+export const App = component$(() => {
+    const message = greeting.text;
+    return (
+        <p class="stuff">{message}</p>
+    );
+});
+"#
+        .to_string(),
+        minify: MinifyMode::Minify,
+        mode: EmitMode::Prod,
+        transpile_ts: true,
+        transpile_jsx: true,
+        ..TestInput::default()
+    });
+}
+
+#[test]
+fn minify_output_is_valid_with_shadowed_bindings() {
+    let res = test_input!(TestInput {
+        code: r#"
+import { component$ } from '@builder.io/qwik';
+
+const format = (value) => {
+    const label = 'Value: ';
+    return label + value;
+};
+
+export const App = component$(() => {
+    const label = 'Qwik';
+    return (
+        <p title={label}>{format(label)}</p>
+    );
+});
+"#
+        .to_string(),
+        minify: MinifyMode::Minify,
+        mode: EmitMode::Prod,
+        transpile_ts: true,
+        transpile_jsx: true,
+        ..TestInput::default()
+    })
+    .unwrap();
+
+    // Inlining `format` must not declare `label` twice in the same scope
+    for module in &res.modules {
+        let reparsed = transform_modules(TransformModulesOptions {
+            input: vec![TransformModuleInput {
+                code: module.code.clone(),
+                path: module.path.clone(),
+                input_source_map: None,
+                vendored: false,
+            }],
+            entry_strategy: EntryStrategy::Inline,
+            ..test_options()
+        })
+        .unwrap();
+        assert!(reparsed.diagnostics.is_empty(), "{}", module.code);
+    }
+}
+
+#[test]
+fn target_downlevels_newer_syntax() {
+    let code = r#"
+import { component$, $ } from '@builder.io/qwik';
+
+export const config = globalThis.qwik?.config ?? {};
+
+export class Store {
+    items = [];
+}
+
+export const App = component$((props) => {
+    const onClick = $(() => console.log(props.user?.name ?? 'anonymous'));
+    const onReset = $(() => console.log({ ...props, user: null }));
+    return <button onClick$={onClick} onDblClick$={onReset}/>;
+});
+"#;
+    // Class fields and object spreads need helpers, inlined in the modules using them
+    for target in [EsTarget::Es2019, EsTarget::Es2017] {
+        test_input!(TestInput {
+            code: code.to_string(),
+            target,
+            transpile_ts: true,
+            transpile_jsx: true,
+            ..TestInput::default()
+        });
+    }
+}
+
+#[test]
+fn stable_symbol_hashes() {
+    let code = r#"
+import { component$ } from '@builder.io/qwik';
+
+export const App = component$(() => {
+    return <p class="stuff">Hello Qwik</p>;
+});
+"#;
+    let res = transform_modules(TransformModulesOptions {
+        input: vec![TransformModuleInput {
+            code: code.into(),
+            path: "test.tsx".into(),
+            input_source_map: None,
+            vendored: false,
+        }],
+        ..test_options()
+    })
+    .unwrap();
+
+    // Changing this hash renames every symbol, see `hash.rs`
+    let hook = res.modules.iter().find_map(|m| m.hook.as_ref()).unwrap();
+    assert_eq!(hook.hash, JsWord::from("ckEPmXZlub0"));
+    assert_eq!(
+        res.get_manifest().hash_algorithm,
+        JsWord::from("siphash13-v1")
+    );
+    let legacy: QwikManifest =
+        serde_json::from_str(r#"{"version":"1","symbols":{},"bundles":{},"mapping":{}}"#).unwrap();
+    assert_eq!(legacy.hash_algorithm, JsWord::from("default-hasher"));
+    assert!(crate::hash::find_hash_collisions(&res).is_empty());
+
+    let mut colliding = res.clone();
+    let mut module = res
+        .modules
+        .iter()
+        .find(|m| m.hook.is_some())
+        .unwrap()
+        .clone();
+    if let Some(hook) = module.hook.as_mut() {
+        hook.origin = "other.tsx".into();
+    }
+    colliding.modules.push(module);
+    let diagnostics = crate::hash::find_hash_collisions(&colliding);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code.as_deref(), Some("C06"));
+    assert_eq!(diagnostics[0].file, JsWord::from("other.tsx"));
+}
+
+#[test]
+fn rules_entry_strategy() {
+    let routes = r#"
+import { component$ } from '@builder.io/qwik';
+
+export const AdminPanel = component$(() => {
+    return <button onClick$={() => console.log('admin')}>Admin</button>;
+});
+
+export const Home = component$(() => {
+    return <div>Home</div>;
+});
+"#;
+    let other = r#"
+import { component$ } from '@builder.io/qwik';
+
+export const Other = component$(() => {
+    return <div>Other</div>;
+});
+"#;
+    let entry_rules: EntryRules = serde_json::from_str(
+        r#"{
+            "rules": [
+                { "entry": "admin", "component": "Admin*" },
+                { "entry": "routes", "origin": "routes/**", "ctxKind": "function" }
+            ],
+            "fallback": "component"
+        }"#,
+    )
+    .unwrap();
+    let res = transform_modules(TransformModulesOptions {
+        input: vec![
+            TransformModuleInput {
+                code: routes.into(),
+                path: "routes/index.tsx".into(),
+                input_source_map: None,
+                vendored: false,
+            },
+            TransformModuleInput {
+                code: other.into(),
+                path: "components/other.tsx".into(),
+                input_source_map: None,
+                vendored: false,
+            },
+        ],
+        entry_rules: Some(entry_rules),
+        entry_strategy: EntryStrategy::Rules,
+        ..test_options()
+    });
+    snapshot_res!(&res, format!("==INPUT==\n\n{}{}", routes, other));
+}
+
+#[test]
+fn rules_entry_strategy_rejects_inline_fallback() {
+    for fallback in ["inline", "hoist"] {
+        let entry_rules: EntryRules = serde_json::from_str(&format!(
+            r#"{{ "rules": [{{ "entry": "admin" }}], "fallback": "{}" }}"#,
+            fallback
+        ))
+        .unwrap();
+        let res = transform_modules(TransformModulesOptions {
+            input: vec![TransformModuleInput {
+                code: "export const a = 1;".into(),
+                path: "test.tsx".into(),
+                input_source_map: None,
+                vendored: false,
+            }],
+            entry_rules: Some(entry_rules),
+            entry_strategy: EntryStrategy::Rules,
+            ..test_options()
+        });
+        assert!(matches!(res, Err(TransformError::InvalidOptions(_))));
+    }
+}
+
+#[test]
+fn profile_entry_strategy() {
+    let code = r#"
+import { component$ } from '@builder.io/qwik';
+
+export const App = component$(() => {
+    return (
+        <div>
+            <button onClick$={() => console.log('open')}>Open</button>
+            <a onClick$={() => console.log('close')}>Close</a>
+            <input onInput$={() => console.log('input')} />
+        </div>
+    );
+});
+"#;
+    let res = transform_modules(TransformModulesOptions {
+        input: vec![TransformModuleInput {
+            code: code.into(),
+            path: "app.tsx".into(),
+            input_source_map: None,
+            vendored: false,
+        }],
+        ..test_options()
+    })
+    .unwrap();
+    let hash = |display_name: &str| {
+        res.modules
+            .iter()
+            .filter_map(|m| m.hook.as_ref())
+            .find(|hook| hook.display_name.as_ref() as &str == display_name)
+            .unwrap()
+            .hash
+            .to_string()
+    };
+
+    // The open and close handlers are used together, the input handler on its own
+    let open = hash("App_component_div_button_onClick");
+    let close = hash("App_component_div_a_onClick");
+    let mut sessions = vec![vec![format!("s_{}", open), close]; 3];
+    sessions.push(vec![open]);
+    sessions.push(vec![hash("App_component_div_input_onInput")]);
+    test_input!(TestInput {
+        code: code.to_string(),
+        filename: "app.tsx".to_string(),
+        entry_strategy: EntryStrategy::Profile,
+        usage_profile: Some(UsageProfile {
+            sessions,
+            ..UsageProfile::default()
+        }),
+        transpile_ts: true,
+        transpile_jsx: true,
+        ..TestInput::default()
+    });
+}
+
+#[test]
+fn size_entry_strategy() {
+    let code = r#"
+import { component$ } from '@builder.io/qwik';
+
+export const App = component$(() => {
+    return (
+        <div>
+            <button onClick$={() => console.log('open')}>Open</button>
+            <input onInput$={() => console.log('input')} />
+        </div>
+    );
+});
+"#;
+    // Segments over the maximum are never merged
+    for max_size in [200_000, 1] {
+        test_input!(TestInput {
+            code: code.to_string(),
+            filename: "components/app.tsx".to_string(),
+            entry_strategy: EntryStrategy::Size,
+            size_limits: Some(SizeLimits {
+                min_size: 100_000,
+                max_size,
+            }),
+            transpile_ts: true,
+            transpile_jsx: true,
+            ..TestInput::default()
+        });
+    }
+}
+
+#[test]
+fn inline_tiny_segments() {
+    let res = test_input!(TestInput {
+        code: r#"
+import { component$, useSignal } from '@builder.io/qwik';
+
+export const Counter = component$(() => {
+    const count = useSignal(0);
+    return <button onClick$={() => count.value++}>{count.value}</button>;
+});
+"#
+        .to_string(),
+        filename: "counter.tsx".to_string(),
+        inline_threshold: Some(40),
+        transpile_ts: true,
+        transpile_jsx: true,
+        ..TestInput::default()
+    })
+    .unwrap();
+
+    let component = res
+        .modules
+        .iter()
+        .find(|m| {
+            m.hook.as_ref().map_or(false, |hook| {
+                hook.display_name.as_ref() as &str == "Counter_component"
+            })
+        })
+        .unwrap();
+    assert_eq!(component.inlined_hooks.len(), 1);
+    let inlined = &component.inlined_hooks[0];
+    assert_eq!(
+        inlined.display_name.as_ref() as &str,
+        "Counter_component_button_onClick"
+    );
+
+    let manifest = res.get_manifest();
+    assert_eq!(
+        manifest.mapping.get(&inlined.name).map(|f| f.to_string()),
+        Some(component.path.clone())
+    );
+    assert!(manifest.bundles[&JsWord::from(component.path.as_str())]
+        .symbols
+        .contains(&inlined.name));
+}
+
+#[test]
+fn custom_entry_policy() {
+    struct ByCtxName;
+
+    impl EntryPolicy for ByCtxName {
+        fn get_entry_for_sym(
+            &self,
+            _hash: &str,
+            _path: &PathData,
+            _context: &[String],
+            hook_data: &HookData,
+        ) -> Option<JsWord> {
+            match hook_data.ctx_kind {
+                HookKind::EventHandler => Some("handlers".into()),
+                _ => None,
+            }
+        }
+    }
+
+    let code = r#"
+import { component$ } from '@builder.io/qwik';
+
+export const App = component$(() => {
+    return <button onClick$={() => console.log('click')}>Click</button>;
+});
+"#;
+    // The policy applies to the segment strategies as well, which do not generate entries otherwise
+    for entry_strategy in [EntryStrategy::Smart, EntryStrategy::Hook] {
+        let res = transform_modules(TransformModulesOptions {
+            input: vec![TransformModuleInput {
+                code: code.into(),
+                path: "app.tsx".into(),
+                input_source_map: None,
+                vendored: false,
+            }],
+            entry_policy: Some(Box::new(ByCtxName)),
+            entry_strategy,
+            ..test_options()
+        });
+        snapshot_res!(&res, format!("==INPUT==\n\n{}", code));
+    }
+}
+
+#[test]
+fn stable_entries_from_previous_manifest() {
+    let transform = |code: &str, entry_strategy, manual_chunks, previous_manifest| {
+        transform_modules(TransformModulesOptions {
+            input: vec![TransformModuleInput {
+                code: code.into(),
+                path: "app.tsx".into(),
+                input_source_map: None,
+                vendored: false,
+            }],
+            manual_chunks,
+            previous_manifest,
+            entry_strategy,
+            ..test_options()
+        })
+        .unwrap()
+    };
+    let hook = |res: &TransformOutput, display_name: &str| {
+        res.modules
+            .iter()
+            .filter_map(|m| m.hook.as_ref())
+            .find(|hook| hook.display_name.as_ref() as &str == display_name)
+            .cloned()
+            .unwrap()
+    };
+
+    let previous = transform(
+        r#"
+import { component$ } from '@builder.io/qwik';
+export const A = component$(() => <button onClick$={() => console.log('a')}>A</button>);
+export const B = component$(() => <div>B</div>);
+"#,
+        EntryStrategy::Component,
+        None,
+        None,
+    );
+    assert_eq!(previous.manifest_diff, None);
+    let a = hook(&previous, "A_component");
+    let a_click = hook(&previous, "A_component_button_onClick");
+    let b = hook(&previous, "B_component");
+
+    let mut manual_chunks = HashMap::new();
+    manual_chunks.insert(a_click.hash.to_string(), JsWord::from("manual"));
+    let res = transform(
+        r#"
+import { component$ } from '@builder.io/qwik';
+export const A = component$(() => <button onClick$={() => console.log('a')}>A</button>);
+export const C = component$(() => <div>C</div>);
+"#,
+        EntryStrategy::Single,
+        Some(manual_chunks),
+        Some(previous.get_manifest()),
+    );
+    let c = hook(&res, "C_component");
+    assert_eq!(hook(&res, "A_component").entry, Some("entry_A".into()));
+    assert_eq!(c.entry, Some("entry_hooks".into()));
+    assert_eq!(
+        res.manifest_diff,
+        Some(ManifestDiff {
+            added: vec![c.name],
+            removed: vec![b.name],
+            moved: vec![MovedSymbol {
+                symbol: a_click.name,
+                from: Some("entry_A".into()),
+                to: Some("manual".into()),
+            }],
+        })
+    );
+    assert_eq!(a.entry, Some("entry_A".into()));
+}
+
+#[test]
+fn content_hash_file_names() {
+    let transform = |code: &str, snapshot| {
+        test_input!(TestInput {
+            code: code.to_string(),
+            filename: "app.tsx".to_string(),
+            content_hash: true,
+            entry_strategy: EntryStrategy::Smart,
+            transpile_ts: true,
+            transpile_jsx: true,
+            snapshot,
+            ..TestInput::default()
+        })
+        .unwrap()
+    };
+    let path = |res: &TransformOutput, display_name: &str| {
+        res.modules
+            .iter()
+            .find(|m| {
+                m.hook.as_ref().map_or(false, |hook| {
+                    hook.display_name.as_ref() as &str == display_name
+                })
+            })
+            .map(|m| m.path.clone())
+            .unwrap()
+    };
+
+    let res = transform(
+        r#"
+import { component$ } from '@builder.io/qwik';
+export const A = component$(() => <button onClick$={() => console.log('a')}>A</button>);
+export const B = component$(() => <div>B</div>);
+"#,
+        true,
+    );
+    let paths: Vec<&str> = res.modules.iter().map(|m| m.path.as_str()).collect();
+    let manifest = res.get_manifest();
+    for file_name in manifest.mapping.values() {
+        assert!(paths.contains(&file_name.as_ref()));
+    }
+
+    let changed = transform(
+        r#"
+import { component$ } from '@builder.io/qwik';
+export const A = component$(() => <button onClick$={() => console.log('b')}>A</button>);
+export const B = component$(() => <div>B</div>);
+"#,
+        false,
+    );
+    let click = "A_component_button_onClick";
+    assert_ne!(path(&res, click), path(&changed, click));
+    // The component imports its click handler, so it is renamed as well
+    assert_ne!(path(&res, "A_component"), path(&changed, "A_component"));
+    assert_eq!(path(&res, "B_component"), path(&changed, "B_component"));
+    // Renamed files keep their entry, so no symbol moved
+    let diff = changed.diff_manifest(&res.get_manifest());
+    assert!(diff.moved.is_empty() && diff.added.is_empty() && diff.removed.is_empty());
+}
+
+#[test]
+fn imports_collected_from_ast() {
+    let res = test_input!(TestInput {
+        code: r#"
+import { component$ } from '@builder.io/qwik';
+
+export const text = 'import("./app_component") from "./other"';
+
+export const App = component$(() => {
+    return <button onClick$={() => console.log(text)}>Click</button>;
+});
+"#
+        .to_string(),
+        filename: "app.tsx".to_string(),
+        content_hash: true,
+        entry_strategy: EntryStrategy::Smart,
+        transpile_ts: true,
+        transpile_jsx: true,
+        ..TestInput::default()
+    })
+    .unwrap();
+
+    // Strings that look like imports are not collected
+    let main = res.modules.iter().find(|m| m.path == "app.js").unwrap();
+    assert!(main
+        .imports
+        .iter()
+        .all(|import| import.specifier.as_ref() as &str != "./other"));
+    let paths: Vec<&str> = res.modules.iter().map(|m| m.path.as_str()).collect();
+    for module in &res.modules {
+        for import in &module.imports {
+            if let Some(file_name) = import.specifier.strip_prefix("./") {
+                assert!(module.code.contains(import.specifier.as_ref() as &str));
+                assert!(
+                    paths.contains(&[file_name, ".js"].concat().as_str()),
+                    "{} imports missing {}",
+                    module.path,
+                    import.specifier
+                );
+            }
+        }
+    }
+    let manifest = res.get_manifest();
+    assert_eq!(
+        manifest.bundles[&JsWord::from("app.js")]
+            .dynamic_imports
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn qrl_base_url() {
+    let code = r#"
+import { component$ } from '@builder.io/qwik';
+
+export const App = component$(() => {
+    return <button onClick$={() => console.log('click')}>Click</button>;
+});
+"#;
+    // The URLs name the emitted files with their extension, even without `explicit_extensions`
+    for (explicit_extensions, entry_strategy) in [
+        (true, EntryStrategy::Hook),
+        (false, EntryStrategy::Hook),
+        (false, EntryStrategy::Single),
+    ] {
+        test_input!(TestInput {
+            code: code.to_string(),
+            filename: "components/app.tsx".to_string(),
+            explicit_extensions,
+            entry_strategy,
+            qrl_base: Some("https://cdn.example.com/build/".to_string()),
+            transpile_ts: true,
+            transpile_jsx: true,
+            ..TestInput::default()
+        });
+    }
+}
+
+#[test]
+fn segment_dir_layout() {
+    let res = test_input!(TestInput {
+        code: r#"
+import { component$ } from '@builder.io/qwik';
+
+const helper = () => console.log('click');
+
+export const App = component$(() => {
+    return <button onClick$={() => helper()}>Click</button>;
+});
+"#
+        .to_string(),
+        filename: "components/app.tsx".to_string(),
+        segment_dir: Some("build/q-".to_string()),
+        entry_strategy: EntryStrategy::Single,
+        transpile_ts: true,
+        transpile_jsx: true,
+        ..TestInput::default()
+    })
+    .unwrap();
+
+    let manifest = res.get_manifest();
+    for module in &res.modules {
+        if let Some(hook) = &module.hook {
+            assert_eq!(manifest.mapping[&hook.name].as_ref() as &str, module.path);
+        }
+    }
+
+    use crate::code_move::split_segment_dir;
+    assert_eq!(split_segment_dir(Some("build/q-")), ("build", "q-"));
+    assert_eq!(split_segment_dir(Some("segments")), ("segments", ""));
+    assert_eq!(split_segment_dir(Some("segments/")), ("segments", ""));
+    assert_eq!(split_segment_dir(Some("./q-")), ("", "q-"));
+    assert_eq!(split_segment_dir(Some("./build/q-")), ("build", "q-"));
+    assert_eq!(split_segment_dir(None), ("", ""));
+}
+
+#[test]
+fn manifest_dependency_graph() {
+    let code = r#"
+import { component$ } from '@builder.io/qwik';
+
+const helper = () => console.log('click');
+
+export const App = component$(() => {
+    return <button onClick$={() => helper()}>Click</button>;
+});
+"#;
+    let res = transform_modules(TransformModulesOptions {
+        input: vec![TransformModuleInput {
+            code: code.into(),
+            path: "app.tsx".into(),
+            input_source_map: None,
+            vendored: false,
+        }],
+        entry_strategy: EntryStrategy::Single,
+        ..test_options()
+    })
+    .unwrap();
+
+    let module = |display_name: &str| {
+        res.modules
+            .iter()
+            .find(|m| {
+                m.hook.as_ref().map_or(false, |hook| {
+                    hook.display_name.as_ref() as &str == display_name
+                })
+            })
+            .unwrap()
+    };
+    let component = module("App_component");
+    let click = module("App_component_button_onClick");
+    let manifest = res.get_manifest();
+    let bundle = |path: &str| &manifest.bundles[&JsWord::from(path)];
+    let entry = JsWord::from("entry_hooks.js");
+
+    let mut members = manifest.entries[&entry].clone();
+    members.sort();
+    let mut expected = vec![
+        JsWord::from(component.path.as_str()),
+        JsWord::from(click.path.as_str()),
+    ];
+    expected.sort();
+    assert_eq!(members, expected);
+    let entry_bundle = bundle("entry_hooks.js");
+    assert_eq!(entry_bundle.imports, expected);
+    assert_eq!(entry_bundle.symbols.len(), 2);
+    assert_eq!(entry_bundle.origins, vec![JsWord::from("app.tsx")]);
+
+    assert_eq!(bundle("app.js").dynamic_imports, vec![entry.clone()]);
+    assert_eq!(bundle(&component.path).dynamic_imports, vec![entry]);
+    assert_eq!(bundle(&click.path).imports, vec![JsWord::from("app.js")]);
+    assert_eq!(
+        bundle(&click.path).symbols,
+        vec![click.hook.as_ref().unwrap().name.clone()]
+    );
+}
+
+#[test]
+fn invalid_files_report_diagnostics() {
+    let component = r#"
+import { component$ } from '@builder.io/qwik';
+
+export const App = component$(() => <div>App</div>);
+"#;
+    let inlined = r#"
+import { inlinedQrl } from '@builder.io/qwik';
+
+export const handler = inlinedQrl(() => console.log('click'), symbolName);
+"#;
+    // The other files are still transformed
+    let res = transform_modules(TransformModulesOptions {
+        input: vec![
+            TransformModuleInput {
+                code: component.into(),
+                path: "app.tsx".into(),
+                input_source_map: None,
+                vendored: false,
+            },
+            TransformModuleInput {
+                code: component.into(),
+                path: "components/README".into(),
+                input_source_map: None,
+                vendored: false,
+            },
+            TransformModuleInput {
+                code: inlined.into(),
+                path: "handler.js".into(),
+                input_source_map: None,
+                vendored: false,
+            },
+        ],
+        ..test_options()
+    });
+    snapshot_res!(&res, format!("==INPUT==\n\n{}{}", component, inlined));
+}
+
+#[test]
+fn unrelated_root_dir_reports_diagnostic() {
+    // A relative path can not be made relative to an absolute root directory
+    test_input!(TestInput {
+        code: r#"
+import { component$ } from '@builder.io/qwik';
+
+export const App = component$(() => <div>App</div>);
+"#
+        .to_string(),
+        filename: "app.tsx".to_string(),
+        src_dir: "src".to_string(),
+        root_dir: Some("/project".to_string()),
+        transpile_ts: true,
+        transpile_jsx: true,
+        ..TestInput::default()
+    });
+}
+
+#[test]
+fn overload_signatures_without_transpile_ts() {
+    test_input!(TestInput {
+        code: r#"
+import { component$ } from '@builder.io/qwik';
+
+export function format(value: string): string;
+export function format(value: number): string;
+export function format(value: string | number) {
+    return String(value);
+}
+
+export const App = component$(() => <div>{format(1)}</div>);
+"#
+        .to_string(),
+        filename: "app.tsx".to_string(),
+        transpile_jsx: true,
+        ..TestInput::default()
+    });
+}
+
+#[test]
+fn recoverable_parse_errors() {
+    let recoverable = r#"
+import { component$ } from '@builder.io/qwik';
+
+with (window) {
+    console.log(location);
+}
+
+export const App = component$(() => <div>App</div>);
+"#;
+    let fatal = r#"
+with (window) {
+    console.log(location);
+}
+
+export const value = ;
+"#;
+    // Recoverable errors are reported, and stop the transform by default.
+    // A fatal error is reported along with the recoverable ones before it
+    for (code, recover_parse_errors) in [(recoverable, false), (recoverable, true), (fatal, true)] {
+        test_input!(TestInput {
+            code: code.to_string(),
+            filename: "app.jsx".to_string(),
+            transpile_ts: true,
+            transpile_jsx: true,
+            recover_parse_errors,
+            ..TestInput::default()
+        });
+    }
+}
+
+#[test]
+fn vendored_diagnostics_are_source_errors() {
+    let code = r#"
+export const value = ;
+"#;
+    // Inputs marked as vendored are reported like the ones of `node_modules`
+    let res = transform_modules(TransformModulesOptions {
+        input: vec![
+            TransformModuleInput {
+                code: code.into(),
+                path: "app.js".into(),
+                input_source_map: None,
+                vendored: false,
+            },
+            TransformModuleInput {
+                code: code.into(),
+                path: "../node_modules/lib/index.qwik.mjs".into(),
+                input_source_map: None,
+                vendored: false,
+            },
+            TransformModuleInput {
+                code: code.into(),
+                path: "vendor/lib.js".into(),
+                input_source_map: None,
+                vendored: true,
+            },
+        ],
+        ..test_options()
+    });
+    snapshot_res!(&res, format!("==INPUT==\n\n{}", code));
+}
+
+#[test]
+fn suppression_comments() {
+    // Only the reference of the segment without suppression is reported
+    test_input!(TestInput {
+        code: r#"
+import { $, component$ } from '@builder.io/qwik';
+/* qwik-optimizer-disable C05 */
+
+export const App = component$(() => {
+    function hola() {}
+    return $(() => {
+        // qwik-optimizer-disable-next-line C02
+        hola();
+        return <div></div>;
+    });
+});
+
+export const Other = component$(() => {
+    function hola() {}
+    return $(() => hola());
+});
+"#
+        .to_string(),
+        filename: "app.tsx".to_string(),
+        transpile_ts: true,
+        transpile_jsx: true,
+        ..TestInput::default()
+    });
+}
+
+#[test]
+fn non_serializable_captures() {
+    // Captures are reported in the order of their names, and the segment is still emitted
+    test_input!(TestInput {
+        code: r#"
+import { component$, useSignal } from '@builder.io/qwik';
+
+export const App = component$(() => {
+    const ws = new WebSocket('wss://example.com');
+    const el = document.querySelector('#app');
+    const count = useSignal(0);
+    const now = new Date();
+    const cart = new Cart();
+    return <button onClick$={() => {
+        ws.send(count.value);
+        el.focus();
+        console.log(now, cart);
+    }}>Send</button>;
+});
+"#
+        .to_string(),
+        filename: "app.tsx".to_string(),
+        transpile_ts: true,
+        transpile_jsx: true,
+        ..TestInput::default()
+    });
+
+    // Type annotations are checked while TypeScript is not stripped
+    test_input!(TestInput {
+        code: r#"
+import { $, component$ } from '@builder.io/qwik';
+
+export const App = component$(() => {
+    let socket: WebSocket | undefined;
+    return $(() => socket?.close());
+});
+"#
+        .to_string(),
+        filename: "app.ts".to_string(),
+        ..TestInput::default()
+    });
+}
+
+#[test]
+fn non_serializable_params_and_destructuring() {
+    // Default values and the elements of destructured literals are checked
+    test_input!(TestInput {
+        code: r#"
+import { $ } from '@builder.io/qwik';
+
+export function useFeed(signal = new AbortController()) {
+    const [feed, count] = [fetch('/feed'), 0];
+    const { el = document.body, label } = { label: 'Feed' };
+    return $(() => {
+        signal.abort();
+        feed.then(() => el.focus());
+        console.log(count, label);
+    });
+}
+"#
+        .to_string(),
+        filename: "feed.tsx".to_string(),
+        transpile_ts: true,
+        transpile_jsx: true,
+        ..TestInput::default()
+    });
+
+    // Like the annotations of the parameters and of their destructured properties
+    test_input!(TestInput {
+        code: r#"
+import { $ } from '@builder.io/qwik';
+
+export const useFocus = (el: HTMLElement, { ws, name }: { ws: WebSocket; name: string }) => {
+    return $(() => {
+        el.focus();
+        ws.send(name);
+    });
+};
+"#
+        .to_string(),
+        filename: "focus.ts".to_string(),
+        ..TestInput::default()
+    });
+}
+
+#[test]
+fn test_glob_match() {
+    use crate::utils::glob_match;
+
+    assert!(glob_match("src/routes/**", "src/routes/index.tsx"));
+    assert!(glob_match(
+        "src/routes/**",
+        "src/routes/admin/users/index.tsx"
+    ));
+    assert!(glob_match("src/**/*.tsx", "src/index.tsx"));
+    assert!(glob_match("src/**/*.tsx", "src/components/button.tsx"));
+    assert!(!glob_match("src/*.tsx", "src/components/button.tsx"));
+    assert!(glob_match("Admin*", "AdminPanel_component"));
+    assert!(glob_match("?pp", "App"));
+    assert!(!glob_match("App", "AppHeader"));
+}
+
+// TODO(misko): Make this test work by implementing strict serialization.
+// #[test]
+// fn example_of_synchronous_qrl_that_cant_be_serialized() {
+//     test_input!(TestInput {
+//         code: r#"
+//         import { sync$, component$ } from "@builder.io/qwik";
+
+//         export default component$(() => {
+//         return (
+//             <input onClick$={sync$(function(event, target) {
+//                 console.log(component$);
+//             })}/>
+//         );
+//         });
+//         "#
+//         .to_string(),
+//         transpile_ts: true,
+//         transpile_jsx: true,
+//         ..TestInput::default()
+//     });
+// }
+
+fn get_hash(name: &str) -> String {
+    name.split('_').last().unwrap().into()
+}
+
+struct TestInput {
+    pub code: String,
+    pub filename: String,
+    pub src_dir: String,
+    pub root_dir: Option<String>,
+    pub manual_chunks: Option<HashMap<String, JsWord>>,
+    pub entry_strategy: EntryStrategy,
+    pub minify: MinifyMode,
+    pub transpile_ts: bool,
+    pub transpile_jsx: bool,
+    pub preserve_filenames: bool,
+    pub explicit_extensions: bool,
+    pub snapshot: bool,
+    pub mode: EmitMode,
+    pub core_module: Option<String>,
+    pub scope: Option<String>,
+    pub strip_exports: Option<Vec<String>>,
+    pub reg_ctx_name: Option<Vec<String>>,
+    pub strip_ctx_name: Option<Vec<String>>,
+    pub strip_event_handlers: bool,
+    pub is_server: Option<bool>,
+    pub input_source_map: Option<String>,
+    pub sources_content: bool,
+    pub target: EsTarget,
+    pub usage_profile: Option<UsageProfile>,
+    pub size_limits: Option<SizeLimits>,
+    pub inline_threshold: Option<usize>,
+    pub content_hash: bool,
+    pub qrl_base: Option<String>,
+    pub segment_dir: Option<String>,
+    pub recover_parse_errors: bool,
+}
+
+impl TestInput {
+    pub fn default() -> Self {
+        Self {
+            filename: "test.tsx".to_string(),
+            src_dir: "/user/qwik/src/".to_string(),
+            root_dir: None,
+            code: "/user/qwik/src/".to_string(),
+            manual_chunks: None,
+            entry_strategy: EntryStrategy::Hook,
+            minify: MinifyMode::Simplify,
+            transpile_ts: false,
+            transpile_jsx: false,
+            preserve_filenames: false,
+            explicit_extensions: false,
+            snapshot: true,
+            mode: EmitMode::Lib,
+            scope: None,
+            core_module: None,
+            reg_ctx_name: None,
+            strip_exports: None,
+            strip_ctx_name: None,
+            strip_event_handlers: false,
+            is_server: None,
+            input_source_map: None,
+            sources_content: false,
+            target: EsTarget::EsNext,
+            usage_profile: None,
+            size_limits: None,
+            inline_threshold: None,
+            content_hash: false,
+            qrl_base: None,
+            segment_dir: None,
+            // Some inputs have typos the parser recovers from
+            recover_parse_errors: true,
+        }
+    }
+}
+
+/// Options of `transform_modules` shared by the tests, which override the fields they exercise.
+fn test_options() -> TransformModulesOptions {
+    TransformModulesOptions {
+        src_dir: "/user/qwik/src/".into(),
+        root_dir: None,
+        input: vec![],
+        source_maps: false,
+        sources_content: false,
+        minify: MinifyMode::Simplify,
+        target: EsTarget::EsNext,
+        transpile_ts: true,
+        transpile_jsx: true,
+        preserve_filenames: false,
+        entry_strategy: EntryStrategy::Hook,
+        entry_policy: None,
+        manual_chunks: None,
+        entry_rules: None,
+        usage_profile: None,
+        size_limits: None,
+        inline_threshold: None,
+        previous_manifest: None,
+        content_hash: false,
+        qrl_base: None,
+        segment_dir: None,
+        recover_parse_errors: false,
+        explicit_extensions: false,
+        mode: EmitMode::Lib,
+        scope: None,
+        core_module: None,
+        strip_exports: None,
+        strip_ctx_name: None,
+        strip_event_handlers: false,
+        reg_ctx_name: None,
+        is_server: None,
+    }
+}
@@ -1,24 +1,25 @@
-use crate::code_move::{fix_path, transform_function_expr};
+use crate::code_move::{fix_path, join_segment_dir, split_segment_dir, transform_function_expr};
 use crate::collector::{
     collect_from_pat, new_ident_from_id, GlobalCollect, Id, IdentCollector, ImportKind,
 };
 use crate::entry_strategy::EntryPolicy;
 use crate::has_branches::{is_conditional_jsx, is_conditional_jsx_block};
+use crate::hash::stable_hasher;
 use crate::inlined_fn::{convert_inlined_fn, render_expr};
 use crate::is_immutable::is_immutable_expr;
+use crate::is_serializable::non_serializable_bindings;
 use crate::parse::{EmitMode, PathData};
 use crate::words::*;
 use crate::{errors, EntryStrategy};
 use base64::Engine;
 use path_slash::PathExt;
 use serde::{Deserialize, Serialize};
-use std::collections::hash_map::DefaultHasher;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Write as _;
 use std::hash::Hash;
 use std::hash::Hasher; // import without risk of name clashing
 use std::iter;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str;
 use swc_atoms::{js_word, JsWord};
 use swc_common::comments::{Comments, SingleThreadedComments};
@@ -26,7 +27,7 @@ use swc_common::SyntaxContext;
 use swc_common::{errors::HANDLER, sync::Lrc, SourceMap, Span, Spanned, DUMMY_SP};
 use swc_ecmascript::ast::{self, PropName};
 use swc_ecmascript::utils::{private_ident, quote_ident, ExprFactory};
-use swc_ecmascript::visit::{noop_fold_type, Fold, FoldWith, VisitWith};
+use swc_ecmascript::visit::{noop_fold_type, noop_visit_type, Fold, FoldWith, Visit, VisitWith};
 
 macro_rules! id {
     ($ident: expr) => {
@@ -63,6 +64,15 @@ pub struct Hook {
     pub span: Span,
 }
 
+/// Segment under the `inline_threshold`, inlined in the module of its parent.
+#[derive(Debug, Clone)]
+pub struct InlinedHook {
+    pub name: JsWord,
+    pub data: HookData,
+    pub span: Span,
+}
+
+/// What is known about a segment when its entry is decided.
 #[derive(Debug, Clone)]
 pub struct HookData {
     pub extension: JsWord,
@@ -89,6 +99,7 @@ pub type IdPlusType = (Id, IdentType);
 #[allow(clippy::module_name_repetitions)]
 pub struct QwikTransform<'a> {
     pub hooks: Vec<Hook>,
+    pub inlined_hooks: Vec<InlinedHook>,
     pub options: QwikTransformOptions<'a>,
 
     hooks_names: HashMap<String, u32>,
@@ -96,6 +107,9 @@ pub struct QwikTransform<'a> {
     extra_bottom_items: BTreeMap<Id, ast::ModuleItem>,
     stack_ctxt: Vec<String>,
     decl_stack: Vec<Vec<IdPlusType>>,
+    /// Variables likely holding a value that can not be serialized, with a description of the
+    /// value and the span of their declaration
+    non_serializable: HashMap<Id, (String, Span)>,
     in_component: bool,
     marker_functions: HashMap<Id, JsWord>,
     jsx_functions: HashSet<Id>,
@@ -126,6 +140,9 @@ pub struct QwikTransformOptions<'a> {
     pub scope: Option<&'a String>,
     pub mode: EmitMode,
     pub entry_strategy: EntryStrategy,
+    pub inline_threshold: Option<usize>,
+    pub qrl_base: Option<&'a str>,
+    pub segment_dir: Option<&'a str>,
     pub reg_ctx_name: Option<&'a [JsWord]>,
     pub strip_ctx_name: Option<&'a [JsWord]>,
     pub strip_event_handlers: bool,
@@ -158,7 +175,7 @@ impl<'a> QwikTransform<'a> {
             }
         }
 
-        let mut hasher = DefaultHasher::new();
+        let mut hasher = stable_hasher();
         let local_file_name = options.path_data.rel_path.to_slash_lossy();
         if let Some(scope) = options.scope {
             hasher.write(scope.as_bytes());
@@ -221,8 +238,10 @@ impl<'a> QwikTransform<'a> {
             jsx_key_counter: 0,
             stack_ctxt: Vec::with_capacity(16),
             decl_stack: Vec::with_capacity(32),
+            non_serializable: HashMap::new(),
             in_component: false,
             hooks: Vec::with_capacity(16),
+            inlined_hooks: vec![],
             hook_stack: Vec::with_capacity(16),
             // extra_top_items: BTreeMap::new(),
             extra_bottom_items: BTreeMap::new(),
@@ -262,6 +281,26 @@ impl<'a> QwikTransform<'a> {
         )
     }
 
+    /// Segments smaller than `inline_threshold` are inlined in their parent, a request of their own
+    /// would cost more than the bytes it saves.
+    fn is_tiny(&self, expr: &ast::Expr) -> bool {
+        self.options
+            .inline_threshold
+            .map_or(false, |threshold| render_expr(expr).len() < threshold)
+    }
+
+    /// Name of the file of a segment, without its extension.
+    fn segment_filename(&self, symbol_name: &JsWord) -> JsWord {
+        let (_, prefix) = split_segment_dir(self.options.segment_dir);
+        JsWord::from([prefix, &get_canonical_filename(symbol_name)].concat())
+    }
+
+    /// Absolute directory segments and entries are emitted in.
+    fn segment_base_dir(&self) -> PathBuf {
+        let (segment_dir, _) = split_segment_dir(self.options.segment_dir);
+        self.options.path_data.base_dir.join(segment_dir)
+    }
+
     fn is_inside_module(&self) -> bool {
         self.hook_stack.is_empty() || self.is_inline()
     }
@@ -332,7 +371,7 @@ impl<'a> QwikTransform<'a> {
         } else {
             write!(display_name, "_{}", index).unwrap();
         }
-        let mut hasher = DefaultHasher::new();
+        let mut hasher = stable_hasher();
         let local_file_name = self.options.path_data.rel_path.to_slash_lossy();
         if let Some(scope) = self.options.scope {
             hasher.write(scope.as_bytes());
@@ -356,6 +395,21 @@ impl<'a> QwikTransform<'a> {
     }
 
     fn handle_inlined_qhook(&mut self, mut node: ast::CallExpr) -> ast::CallExpr {
+        let symbol_name = match node.args.get(1).map(|arg| &*arg.expr) {
+            Some(ast::Expr::Lit(ast::Lit::Str(string))) => string.value.clone(),
+            _ => {
+                HANDLER.with(|handler| {
+                    handler
+                        .struct_span_err_with_code(
+                            node.span,
+                            "inlinedQrl() requires the symbol name as a string literal in its second argument",
+                            errors::get_diagnostic_id(errors::Error::InvalidInlinedQrl),
+                        )
+                        .emit();
+                });
+                return node.fold_children_with(self);
+            }
+        };
         node.args.reverse();
 
         let last_stack = self
@@ -376,26 +430,15 @@ impl<'a> QwikTransform<'a> {
         let first_arg = node
             .args
             .pop()
-            .expect("inlinedQrl() should always have the first argument");
-
-        let second_arg = node
-            .args
-            .pop()
-            .expect("inlinedQrl() should always have the second argument");
-
+            .expect("inlinedQrl() has a second argument, so it has a first one");
+        node.args.pop();
         let third_arg = node.args.pop();
         let span = first_arg.span();
 
-        let (symbol_name, display_name, hash) = {
-            let symbol_name = match *second_arg.expr {
-                ast::Expr::Lit(ast::Lit::Str(string)) => string.value,
-                _ => panic!("dfd"),
-            };
-            parse_symbol_name(
-                symbol_name,
-                matches!(self.options.mode, EmitMode::Dev | EmitMode::Lib),
-            )
-        };
+        let (symbol_name, display_name, hash) = parse_symbol_name(
+            symbol_name,
+            matches!(self.options.mode, EmitMode::Dev | EmitMode::Lib),
+        );
 
         self.hook_stack.push(symbol_name.clone());
         let folded = *first_arg.expr.fold_with(self);
@@ -407,8 +450,8 @@ impl<'a> QwikTransform<'a> {
                     ast::Expr::Array(array) => array
                         .elems
                         .iter()
-                        .flat_map(|item| match &*item.as_ref().unwrap().expr {
-                            ast::Expr::Ident(ident) => Some(id!(ident)),
+                        .flat_map(|item| match item.as_ref().map(|item| &*item.expr) {
+                            Some(ast::Expr::Ident(ident)) => Some(id!(ident)),
                             _ => None,
                         })
                         .collect(),
@@ -647,6 +690,22 @@ impl<'a> QwikTransform<'a> {
             });
             scoped_idents = vec![];
         }
+        for id in &scoped_idents {
+            if let Some((description, decl_span)) = self.non_serializable.get(id) {
+                HANDLER.with(|handler| {
+                    handler
+                        .struct_span_warn_with_code(
+                            *decl_span,
+                            &format!(
+                                "Variable '{}' captured by segment '{}' holds {}, which likely can not be serialized",
+                                id.0, display_name, description
+                            ),
+                            errors::get_diagnostic_id(errors::Error::NonSerializableCapture),
+                        )
+                        .emit();
+                });
+            }
+        }
         let hook_data = HookData {
             extension: self.options.extension.clone(),
             local_idents,
@@ -669,7 +728,8 @@ impl<'a> QwikTransform<'a> {
                     if invalid_decl.iter().any(|entry| entry.0 == *id) {
                         HANDLER.with(|handler| {
                             handler
-                                .struct_err_with_code(
+                                .struct_span_err_with_code(
+                                    find_reference_span(&folded, id).unwrap_or(first_arg_span),
                                     &format!(
                                         "Reference to identifier '{}' can not be used inside a Qrl($) scope because it's a function",
                                         id.0
@@ -684,7 +744,7 @@ impl<'a> QwikTransform<'a> {
         }
         if !should_emit {
             (self.create_noop_qrl(&symbol_name, hook_data), immutable)
-        } else if self.is_inline() {
+        } else if self.is_inline() || self.is_tiny(&folded) {
             let folded = if !hook_data.scoped_idents.is_empty() {
                 let new_local = self.ensure_core_import(&USE_LEXICAL_SCOPE);
                 transform_function_expr(folded, &new_local, &hook_data.scoped_idents)
@@ -744,7 +804,7 @@ impl<'a> QwikTransform<'a> {
         span: Span,
         hook_hash: u64,
     ) -> ast::CallExpr {
-        let canonical_filename = get_canonical_filename(&symbol_name);
+        let canonical_filename = self.segment_filename(&symbol_name);
 
         let entry = self
             .options
@@ -757,28 +817,37 @@ impl<'a> QwikTransform<'a> {
             )
             .map(|entry| JsWord::from(escape_sym(entry.as_ref())));
 
-        let mut filename = format!(
-            "./{}",
-            entry
-                .as_ref()
-                .map(|e| e.as_ref())
-                .unwrap_or(&canonical_filename)
-        );
+        let (segment_dir, prefix) = split_segment_dir(self.options.segment_dir);
+        let mut filename = match &entry {
+            Some(entry) => format!("./{}{}", prefix, entry),
+            None => format!("./{}", canonical_filename),
+        };
         if self.options.explicit_extensions {
             filename.push('.');
             filename.push_str(&self.options.extension);
         }
         let inside_hook = !self.hook_stack.is_empty();
-        let import_path = if inside_hook {
+        let import_path = if let Some(qrl_base) = self.options.qrl_base {
+            // `qrl_base` is the URL of the root of the output, bundlers do not resolve absolute
+            // URLs so they always name the emitted file with its extension
+            let file_name = match &entry {
+                Some(entry) => format!("{}{}.js", prefix, entry),
+                None => format!("{}.{}", canonical_filename, self.options.extension),
+            };
+            JsWord::from(format!(
+                "{}/{}",
+                qrl_base.trim_end_matches('/'),
+                join_segment_dir(segment_dir, &file_name)
+            ))
+        } else if inside_hook {
             fix_path("a", "a", &filename)
         } else {
             fix_path(
-                &self.options.path_data.base_dir,
+                self.segment_base_dir(),
                 &self.options.path_data.abs_dir,
                 &filename,
             )
-        }
-        .unwrap();
+        };
 
         let o = self.create_qrl(import_path, &symbol_name, &hook_data, &span);
         self.hooks.push(Hook {
@@ -1002,8 +1071,15 @@ impl<'a> QwikTransform<'a> {
         symbol_name: JsWord,
         span: Span,
     ) -> ast::CallExpr {
-        let should_inline = matches!(self.options.entry_strategy, EntryStrategy::Inline)
+        let should_inline = !matches!(self.options.entry_strategy, EntryStrategy::Hoist)
             || matches!(expr, ast::Expr::Ident(_));
+        if !self.is_inline() {
+            self.inlined_hooks.push(InlinedHook {
+                name: symbol_name.clone(),
+                data: hook_data.clone(),
+                span,
+            });
+        }
         let inlined_expr = if should_inline {
             expr
         } else {
@@ -1011,7 +1087,7 @@ impl<'a> QwikTransform<'a> {
             self.hooks.push(Hook {
                 entry: None,
                 span,
-                canonical_filename: get_canonical_filename(&symbol_name),
+                canonical_filename: self.segment_filename(&symbol_name),
                 name: symbol_name.clone(),
                 data: hook_data.clone(),
                 expr: Box::new(expr),
@@ -1097,10 +1173,9 @@ impl<'a> QwikTransform<'a> {
             if let ast::Expr::Lit(ast::Lit::Str(string)) = &*expr_spread.expr {
                 let new_value = fix_path(
                     &self.options.path_data.abs_dir,
-                    &self.options.path_data.base_dir,
+                    self.segment_base_dir(),
                     string.value.as_ref(),
-                )
-                .unwrap();
+                );
 
                 return ast::CallExpr {
                     args: vec![ast::ExprOrSpread {
@@ -1729,6 +1804,22 @@ impl<'a> QwikTransform<'a> {
         }
         self.create_internal_call(&_NOOP_QRL, args, true)
     }
+
+    /// Remembers the bindings of `pat` that likely hold values that can not be serialized, they
+    /// are reported at `span` when a segment captures them, or at the binding itself.
+    fn track_non_serializable(
+        &mut self,
+        pat: &ast::Pat,
+        init: Option<&ast::Expr>,
+        span: Option<Span>,
+    ) {
+        let mut bindings = vec![];
+        non_serializable_bindings(pat, init, None, &mut bindings);
+        for (ident, description) in bindings {
+            self.non_serializable
+                .insert(id!(ident), (description, span.unwrap_or(ident.span)));
+        }
+    }
 }
 
 impl<'a> Fold for QwikTransform<'a> {
@@ -1791,6 +1882,9 @@ impl<'a> Fold for QwikTransform<'a> {
 
     // Variable tracking
     fn fold_var_decl(&mut self, node: ast::VarDecl) -> ast::VarDecl {
+        for decl in &node.decls {
+            self.track_non_serializable(&decl.name, decl.init.as_deref(), Some(decl.span));
+        }
         if let Some(current_scope) = self.decl_stack.last_mut() {
             for decl in &node.decls {
                 let mut identifiers = Vec::with_capacity(node.decls.len() + 2);
@@ -1834,6 +1928,14 @@ impl<'a> Fold for QwikTransform<'a> {
     }
 
     fn fold_function(&mut self, node: ast::Function) -> ast::Function {
+        // Overload signatures have no body, they are left as is while TypeScript is not stripped
+        let body = match &node.body {
+            Some(body) => body,
+            None => return node,
+        };
+        for param in &node.params {
+            self.track_non_serializable(&param.pat, None, None);
+        }
         self.decl_stack.push(vec![]);
         let prev = self.root_jsx_mode;
         self.root_jsx_mode = true;
@@ -1843,11 +1945,8 @@ impl<'a> Fold for QwikTransform<'a> {
 
         let is_component = self.in_component;
         self.in_component = false;
-        let is_condition = is_conditional_jsx_block(
-            node.body.as_ref().unwrap(),
-            &self.jsx_functions,
-            &self.immutable_function_cmp,
-        );
+        let is_condition =
+            is_conditional_jsx_block(body, &self.jsx_functions, &self.immutable_function_cmp);
         let current_scope = self
             .decl_stack
             .last_mut()
@@ -1887,6 +1986,9 @@ impl<'a> Fold for QwikTransform<'a> {
     }
 
     fn fold_arrow_expr(&mut self, node: ast::ArrowExpr) -> ast::ArrowExpr {
+        for param in &node.params {
+            self.track_non_serializable(param, None, None);
+        }
         self.decl_stack.push(vec![]);
         let prev = self.root_jsx_mode;
         self.root_jsx_mode = true;
@@ -2333,6 +2435,28 @@ fn compute_scoped_idents(all_idents: &[Id], all_decl: &[IdPlusType]) -> (Vec<Id>
     (output, immutable)
 }
 
+/// Returns the span of the first reference to `id` in `expr`.
+fn find_reference_span(expr: &ast::Expr, id: &Id) -> Option<Span> {
+    struct ReferenceFinder<'a> {
+        id: &'a Id,
+        span: Option<Span>,
+    }
+
+    impl<'a> Visit for ReferenceFinder<'a> {
+        noop_visit_type!();
+
+        fn visit_ident(&mut self, node: &ast::Ident) {
+            if self.span.is_none() && id!(node) == *self.id {
+                self.span = Some(node.span);
+            }
+        }
+    }
+
+    let mut finder = ReferenceFinder { id, span: None };
+    expr.visit_with(&mut finder);
+    finder.span
+}
+
 fn get_canonical_filename(symbol_name: &JsWord) -> JsWord {
     JsWord::from(symbol_name.as_ref().to_ascii_lowercase())
 }
@@ -2360,7 +2484,7 @@ fn get_qrl_dev_obj(asb_path: &Path, hook: &HookData, span: &Span) -> ast::Expr {
                 key: ast::PropName::Ident(ast::Ident::new(js_word!("file"), DUMMY_SP)),
                 value: Box::new(ast::Expr::Lit(ast::Lit::Str(ast::Str {
                     span: DUMMY_SP,
-                    value: asb_path.to_str().unwrap().into(),
+                    value: asb_path.to_string_lossy().as_ref().into(),
                     raw: None,
                 }))),
             }))),
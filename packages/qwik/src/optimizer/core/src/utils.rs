@@ -5,12 +5,12 @@ use swc_atoms::JsWord;
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct SourceLocation {
-    lo: usize,
-    hi: usize,
-    start_line: usize,
-    start_col: usize,
-    end_line: usize,
-    end_col: usize,
+    pub lo: usize,
+    pub hi: usize,
+    pub start_line: usize,
+    pub start_col: usize,
+    pub end_line: usize,
+    pub end_col: usize,
 }
 
 impl SourceLocation {
@@ -41,7 +41,7 @@ impl PartialOrd for SourceLocation {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Diagnostic {
     pub category: DiagnosticCategory,
@@ -53,7 +53,7 @@ pub struct Diagnostic {
     pub scope: DiagnosticScope,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum DiagnosticCategory {
     /// Fails the build with an error.
@@ -64,7 +64,7 @@ pub enum DiagnosticCategory {
     SourceError,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum DiagnosticScope {
     Optimizer,
@@ -76,3 +76,27 @@ pub enum SourceType {
     Script,
     Module,
 }
+
+/// Matches `text` against a glob `pattern`, where `*` matches any sequence of characters except
+/// `/`, `**` matches any sequence including `/`, and `?` matches a single character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[char], text: &[char]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some(('*', rest)) => {
+                let (cross_dirs, rest) = match rest.split_first() {
+                    Some(('*', rest)) => (true, rest.strip_prefix(&['/']).unwrap_or(rest)),
+                    _ => (false, rest),
+                };
+                (0..=text.len())
+                    .take_while(|&i| cross_dirs || i == 0 || text[i - 1] != '/')
+                    .any(|i| matches(rest, &text[i..]))
+            }
+            Some(('?', rest)) => !text.is_empty() && text[0] != '/' && matches(rest, &text[1..]),
+            Some((c, rest)) => text.first() == Some(c) && matches(rest, &text[1..]),
+        }
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches(&pattern, &text)
+}
@@ -5,22 +5,28 @@ export type {
   ComponentEntryStrategy,
   Diagnostic,
   DiagnosticCategory,
+  EntryRule,
   EntryStrategy,
+  EsTarget,
   GlobalInjections,
   HookAnalysis,
   HookEntryStrategy,
   InlineEntryStrategy,
   InsightManifest,
   MinifyMode,
+  ModuleImport,
   Optimizer,
   OptimizerOptions,
   OptimizerSystem,
   Path,
+  ProfileEntryStrategy,
   QwikBundle,
   QwikManifest,
   QwikSymbol,
   ResolvedManifest,
+  RulesEntryStrategy,
   SingleEntryStrategy,
+  SizeEntryStrategy,
   SmartEntryStrategy,
   SourceLocation,
   SourceMapsOption,
@@ -34,6 +40,7 @@ export type {
   TransformOptions,
   TransformOutput,
   TranspileOption,
+  UsageProfile,
 } from './types';
 
 export type { QwikBuildMode, QwikBuildTarget } from './plugins/plugin';
@@ -9,6 +9,9 @@ import type {
   QwikSymbol,
 } from './types';
 
+/** Algorithm the optimizer computes the symbol hashes with, `HASH_ALGORITHM` of its core */
+const HASH_ALGORITHM = 'siphash13-v1';
+
 // This is just the initial prioritization of the symbols and entries
 // at build time so there's less work during each SSR. However, SSR should
 // still further optimize the priorities depending on the user/document.
@@ -257,6 +260,7 @@ export function generateManifestFromBundles(
     bundles: {},
     injections,
     version: '1',
+    hashAlgorithm: HASH_ALGORITHM,
     options: {
       target: opts.target,
       buildMode: opts.buildMode,
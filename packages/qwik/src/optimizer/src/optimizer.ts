@@ -73,11 +73,18 @@ const transformFsAsync = async (
       rootDir: fsOpts.rootDir!,
       entryStrategy: fsOpts.entryStrategy!,
       minify: fsOpts.minify!,
+      target: fsOpts.target!,
       sourceMaps: fsOpts.sourceMaps!,
+      sourcesContent: fsOpts.sourcesContent!,
       transpileTs: fsOpts.transpileTs!,
       transpileJsx: fsOpts.transpileJsx!,
       explicitExtensions: fsOpts.explicitExtensions!,
       preserveFilenames: fsOpts.preserveFilenames!,
+      inlineThreshold: fsOpts.inlineThreshold!,
+      contentHash: fsOpts.contentHash!,
+      qrlBase: fsOpts.qrlBase!,
+      segmentDir: fsOpts.segmentDir!,
+      recoverParseErrors: fsOpts.recoverParseErrors!,
       mode: fsOpts.mode!,
       scope: fsOpts.scope!,
       input,
@@ -97,6 +104,7 @@ const convertOptions = (opts: any) => {
   const output: any = {
     minify: 'simplify',
     sourceMaps: false,
+    sourcesContent: false,
     transpileTs: false,
     transpileJsx: false,
     preserveFilenames: false,
@@ -118,5 +126,12 @@ const convertOptions = (opts: any) => {
   });
   output.entryStrategy = opts.entryStrategy?.type ?? 'smart';
   output.manualChunks = opts.entryStrategy?.manual ?? undefined;
+  output.entryRules =
+    opts.entryStrategy?.type === 'rules'
+      ? { rules: opts.entryStrategy.rules, fallback: opts.entryStrategy.fallback ?? 'smart' }
+      : undefined;
+  output.usageProfile =
+    opts.entryStrategy?.type === 'profile' ? opts.entryStrategy.profile : undefined;
+  output.sizeLimits = opts.entryStrategy?.type === 'size' ? opts.entryStrategy : undefined;
   return output;
 };
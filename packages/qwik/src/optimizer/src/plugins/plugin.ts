@@ -722,7 +722,7 @@ export function createPlugin(optimizerOptions: OptimizerOptions = {}) {
 
       const hooks = Array.from(results.values())
         .flatMap((r) => r.modules)
-        .map((mod) => mod.hook)
+        .flatMap((mod) => [mod.hook, ...(mod.inlinedHooks ?? [])])
         .filter((h) => !!h) as HookAnalysis[];
 
       const manifest = generateManifestFromBundles(path, hooks, injections, outputBundles, opts);
@@ -51,13 +51,35 @@ export interface TransformOptions {
   rootDir?: string;
   entryStrategy?: EntryStrategy;
   minify?: MinifyMode;
+  target?: EsTarget;
   sourceMaps?: boolean;
+  sourcesContent?: boolean;
   transpileTs?: boolean;
   transpileJsx?: boolean;
   preserveFilenames?: boolean;
   explicitExtensions?: boolean;
   mode?: EmitMode;
   scope?: string;
+  /** Segments whose code is smaller than this many bytes are inlined in their parent module */
+  inlineThreshold?: number;
+  /** Emits segments and entries as `[name].[contenthash].js`, rewriting every import of them */
+  contentHash?: boolean;
+  /**
+   * Base URL the QRLs import segments from, like `https://cdn.example.com/build/`, with the
+   * extension of the emitted files. QRLs import them relatively to the importing module by default
+   */
+  qrlBase?: string;
+  /**
+   * Directory segments and entries are emitted in, relative to the root of the output, with the
+   * prefix of their file names: `build/q-` emits `build/q-<name>.js` files. A value without `/` is
+   * a directory, `./q-` sets a prefix alone
+   */
+  segmentDir?: string;
+  /**
+   * Keeps transforming files the parser only found recoverable syntax errors in, their
+   * diagnostics are still reported
+   */
+  recoverParseErrors?: boolean;
   stripExports?: string[];
   regCtxName?: string[];
   stripCtxName?: string[];
@@ -81,6 +103,12 @@ export interface TransformFsOptions extends TransformOptions {
 export interface TransformModuleInput {
   path: string;
   code: string;
+  inputSourceMap?: string;
+  /**
+   * The module is third-party code, its errors are reported as `SourceError` so they do not fail
+   * the build. Modules in `node_modules` always are.
+   */
+  vendored?: boolean;
 }
 
 // RESULT ***************
@@ -118,9 +146,20 @@ export interface TransformModule {
   code: string;
   map: string | null;
   hook: HookAnalysis | null;
+  /** Segments inlined in this module, see `inlineThreshold` */
+  inlinedHooks: HookAnalysis[];
+  /** Imports of the module, collected from its AST, in order */
+  imports: ModuleImport[];
   origPath: string | null;
 }
 
+/** @public */
+export interface ModuleImport {
+  specifier: string;
+  /** Imported by an `import()` call, loading the module on demand */
+  dynamic: boolean;
+}
+
 // DIAGNOSTICS ***************
 
 /** @public */
@@ -156,10 +195,16 @@ export type EntryStrategy =
   | SingleEntryStrategy
   | HookEntryStrategy
   | ComponentEntryStrategy
-  | SmartEntryStrategy;
+  | SmartEntryStrategy
+  | RulesEntryStrategy
+  | ProfileEntryStrategy
+  | SizeEntryStrategy;
+
+/** @public */
+export type MinifyMode = 'minify' | 'simplify' | 'none';
 
 /** @public */
-export type MinifyMode = 'simplify' | 'none';
+export type EsTarget = 'es2017' | 'es2018' | 'es2019' | 'es2020' | 'es2021' | 'es2022' | 'esnext';
 
 /** @public */
 export type EmitMode = 'dev' | 'prod' | 'lib';
@@ -198,6 +243,68 @@ export interface SmartEntryStrategy {
   manual?: Record<string, string>;
 }
 
+/** @public */
+export interface RulesEntryStrategy {
+  type: 'rules';
+  rules: EntryRule[];
+  fallback?: 'single' | 'hook' | 'component' | 'smart';
+  manual?: Record<string, string>;
+}
+
+/**
+ * Assigns `entry` to the segments meeting every condition that is set, `origin`, `displayName`
+ * and `component` are globs.
+ *
+ * @public
+ */
+export interface EntryRule {
+  entry: string;
+  origin?: string;
+  ctxName?: string;
+  ctxKind?: 'function' | 'eventHandler' | 'jSXProp';
+  displayName?: string;
+  component?: string;
+}
+
+/** @public */
+export interface ProfileEntryStrategy {
+  type: 'profile';
+  profile: UsageProfile;
+  manual?: Record<string, string>;
+}
+
+/**
+ * Symbols requested together by real sessions of the application, either as raw `sessions` traces
+ * or aggregated in `coUsage` and `usage`. Symbols are given as their hash or their symbol name.
+ *
+ * @public
+ */
+export interface UsageProfile {
+  sessions?: string[][];
+  coUsage?: { symbols: [string, string]; count: number }[];
+  usage?: Record<string, number>;
+  /**
+   * Fraction of the sessions of the least used symbol of a pair that must also request the other
+   * one for both to be bundled together, defaults to 0.5
+   */
+  affinity?: number;
+  /** Pairs requested together by fewer sessions are never bundled together, defaults to 2 */
+  minSessions?: number;
+}
+
+/**
+ * Merges the emitted segments of every origin into entries of at least `minSize` bytes, without
+ * going over `maxSize`.
+ *
+ * @public
+ */
+export interface SizeEntryStrategy {
+  type: 'size';
+  minSize?: number;
+  maxSize?: number;
+  manual?: Record<string, string>;
+}
+
 /** @public */
 export interface QwikManifest {
   manifestHash: string;
@@ -206,6 +313,7 @@ export interface QwikManifest {
   bundles: { [fileName: string]: QwikBundle };
   injections?: GlobalInjections[];
   version: string;
+  hashAlgorithm?: string;
   options?: {
     target?: string;
     buildMode?: string;